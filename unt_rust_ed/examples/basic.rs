@@ -33,7 +33,8 @@ fn main() {
 
     let project = UntrustedRustProject::new(rust_code)
         .with_target(WasmCompileTarget::Wasi)
-        .with_max_memory_bytes(1 * 1024 * 1024) // 1 MB
+        .with_max_memory_bytes(4 * 1024 * 1024) // 4 MB, including the ~1 MB stack
+        .with_max_heap_bytes(1024 * 1024) // 1 MB
         .with_runtime_timeout_ms(5 * 1000) // 5 sec
         .with_exported_host_type::<Inputs>()
         .with_exported_host_type::<Outputs>();
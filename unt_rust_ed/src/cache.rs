@@ -0,0 +1,576 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, warn};
+
+use serde::{Serialize, Deserialize};
+use tempfile::{Builder, NamedTempFile};
+
+use crate::{CompiledUntrustedRustProject, WasmCompileTarget};
+use crate::error::*;
+
+/// Extension of every cached compiled project file
+pub(crate) const CACHE_FILE_EXTENSION: &str = "unt-rust-ed-c";
+
+/// Extension of the directory with the native code of a cached compiled project file, next to that file
+pub(crate) const NATIVE_CODE_DIR_EXTENSION: &str = "unt-rust-ed-native";
+
+/// Name of the directory with the native code of the compiled projects in a [`CompileCache`], which has a directory per project hash
+pub(crate) const NATIVE_CODE_DIR_NAME: &str = "native";
+
+/// Config of the wasm runtime's compilation cache, in a native code directory
+const NATIVE_CODE_CONFIG_FILE_NAME: &str = "cache-config.toml";
+
+/// Held while reading (shared) or changing (exclusive) the cache directory
+const LOCK_FILE_NAME: &str = ".unt-rust-ed-lock";
+
+/// Prefix of the temp files that compiled projects are written to, before they are renamed into place
+const TEMP_FILE_PREFIX: &str = ".unt-rust-ed-tmp";
+
+/// Everything that can change the wasm built for an [`crate::UntrustedRustProject`].
+/// If any of these differ, then a cached compiled project cannot be reused
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CompileCacheKey {
+    /// sha256 of the untrusted rust code
+    pub rust_code_hash: String,
+    pub target: WasmCompileTarget,
+    /// sorted (typename, typedef) pairs
+    pub exported_host_types: Vec<(String, String)>,
+    /// sorted (trait name, traitdef) pairs
+    pub exported_host_traits: Vec<(String, String)>,
+    /// sorted signatures of the host functions, which get stubs in the guest
+    pub host_functions: Vec<String>,
+    /// sorted
+    pub sdk_types: Vec<String>,
+    /// sorted
+    pub dependencies: Vec<String>,
+    pub export_policy: String,
+    /// sorted `name: signature` of the required and optional exports
+    pub declared_exports: Vec<String>,
+    pub max_heap_bytes: Option<usize>,
+    /// `Debug` output of each source policy, since a cached project has only passed the policies it was built with
+    pub policies: Vec<String>,
+    /// sha256 of the generated Cargo.toml
+    pub cargo_toml_hash: String,
+    /// output of `rustc --version`
+    pub rustc_version: String,
+    /// output of `cargo --version`
+    pub cargo_version: String,
+    /// version of this crate, since it decides how the untrusted code gets rewritten
+    pub crate_version: String,
+}
+
+impl CompileCacheKey {
+    pub fn hash(&self) -> String {
+        return sha256::digest(format!("{:?}", self));
+    }
+
+    /// Returns the names of the components that differ between the two keys.
+    /// This is useful for figuring out why a cached compiled project was not used
+    pub fn diff(&self, other: &CompileCacheKey) -> Vec<&'static str> {
+        let mut mismatched = Vec::new();
+
+        if self.rust_code_hash != other.rust_code_hash {
+            mismatched.push("rust_code_hash");
+        }
+        if self.target != other.target {
+            mismatched.push("target");
+        }
+        if self.exported_host_types != other.exported_host_types {
+            mismatched.push("exported_host_types");
+        }
+        if self.exported_host_traits != other.exported_host_traits {
+            mismatched.push("exported_host_traits");
+        }
+        if self.host_functions != other.host_functions {
+            mismatched.push("host_functions");
+        }
+        if self.sdk_types != other.sdk_types {
+            mismatched.push("sdk_types");
+        }
+        if self.dependencies != other.dependencies {
+            mismatched.push("dependencies");
+        }
+        if self.export_policy != other.export_policy {
+            mismatched.push("export_policy");
+        }
+        if self.declared_exports != other.declared_exports {
+            mismatched.push("declared_exports");
+        }
+        if self.max_heap_bytes != other.max_heap_bytes {
+            mismatched.push("max_heap_bytes");
+        }
+        if self.policies != other.policies {
+            mismatched.push("policies");
+        }
+        if self.cargo_toml_hash != other.cargo_toml_hash {
+            mismatched.push("cargo_toml_hash");
+        }
+        if self.rustc_version != other.rustc_version {
+            mismatched.push("rustc_version");
+        }
+        if self.cargo_version != other.cargo_version {
+            mismatched.push("cargo_version");
+        }
+        if self.crate_version != other.crate_version {
+            mismatched.push("crate_version");
+        }
+
+        return mismatched;
+    }
+}
+
+/// Returns the `(rustc, cargo)` versions used to build the untrusted code.
+/// These are only looked up once per process, since the toolchain will not change underneath us
+pub(crate) fn toolchain_versions() -> Result<(String, String)> {
+    static TOOLCHAIN_VERSIONS: OnceLock<(String, String)> = OnceLock::new();
+
+    if let Some(versions) = TOOLCHAIN_VERSIONS.get() {
+        return Ok(versions.clone());
+    }
+
+    let versions = (tool_version("rustc")?, tool_version("cargo")?);
+
+    return Ok(TOOLCHAIN_VERSIONS.get_or_init(|| versions).clone());
+}
+
+fn tool_version(tool: &str) -> Result<String> {
+    let output = Command::new(tool)
+        .arg("--version")
+        .output().map_err(|err| UntRustedError::IoError {
+            resource: format!("{} --version", tool),
+            err,
+        })?;
+
+    // e.g. a rustup proxy without a default toolchain, which prints nothing useful on stdout
+    if !output.status.success() {
+        return Err(UntRustedError::IoError {
+            resource: format!("{} --version", tool),
+            err: std::io::Error::other(format!("{}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim())),
+        });
+    }
+
+    return Ok(String::from_utf8_lossy(&output.stdout).trim().to_string());
+}
+
+/// Locks the directory against other processes using it (shared or exclusive), until the returned file is dropped
+pub(crate) fn lock_dir(dir: &Path, exclusive: bool) -> Result<File> {
+    let lock_path = dir.join(LOCK_FILE_NAME);
+    let lock_file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|err| UntRustedError::IoError { resource: format!("{:?}", lock_path), err })?;
+
+    let res = if exclusive {
+        lock_file.lock()
+    } else {
+        lock_file.lock_shared()
+    };
+    res.map_err(|err| UntRustedError::IoError { resource: format!("{:?}", lock_path), err })?;
+
+    // the lock is released when the file is dropped
+    return Ok(lock_file);
+}
+
+/// Reads a compiled project written by [`write_compiled_project`]
+pub(crate) fn read_compiled_project(path: &Path) -> Result<CompiledUntrustedRustProject> {
+    let fname = path.display().to_string();
+
+    let mut file = File::open(path)
+        .map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+
+    let reader = flexbuffers::Reader::get_root(buf.as_slice()).map_err(|err| UntRustedError::SerdeReader(fname.clone(), err))?;
+
+    let compiled_project = CompiledUntrustedRustProject::deserialize(reader).map_err(|err| UntRustedError::SerdeDeserialize(fname.clone(), err))?;
+
+    return Ok(compiled_project);
+}
+
+/// Writes the compiled project to a temp file next to `path`, and then renames it into place.
+/// This way readers never see a half-written file
+pub(crate) fn write_compiled_project(path: &Path, compiled_project: &CompiledUntrustedRustProject) -> Result<()> {
+    let fname = path.display().to_string();
+
+    let mut s = flexbuffers::FlexbufferSerializer::new();
+    compiled_project.serialize(&mut s).map_err(|err| UntRustedError::SerdeSerialize(fname.clone(), err))?;
+
+    let parent_dir = match path.parent() {
+        Some(parent_dir) if !parent_dir.as_os_str().is_empty() => parent_dir,
+        _ => Path::new("."),
+    };
+
+    let mut tmp_file = Builder::new()
+        .prefix(TEMP_FILE_PREFIX)
+        .tempfile_in(parent_dir)
+        .map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+
+    tmp_file.write_all(s.view()).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+
+    tmp_file.persist(path).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err: err.error })?;
+
+    return Ok(());
+}
+
+/// Sets up `dir` for the wasm runtime's compilation cache, and returns the path of its config.
+/// The runtime keys the native code by its own version and the CPU features, and compiles the wasm again if the cached code does not fit
+pub(crate) fn native_code_cache_config(dir: &Path) -> Result<PathBuf> {
+    let modules_dir = dir.join("modules");
+    create_private_dir(dir)?;
+    create_private_dir(&modules_dir)?;
+
+    // the runtime only takes an absolute path
+    let modules_dir = modules_dir.canonicalize().map_err(|err| UntRustedError::IoError { resource: format!("{:?}", modules_dir), err })?;
+
+    // a json string is also a valid toml string
+    let directory = serde_json::to_string(&modules_dir).map_err(|err| UntRustedError::SerdeJson(format!("{:?}", modules_dir), err))?;
+    let config = format!("[cache]\nenabled = true\ndirectory = {}\n", directory);
+
+    let config_path = dir.join(NATIVE_CODE_CONFIG_FILE_NAME);
+    if fs::read_to_string(&config_path).is_ok_and(|existing_config| existing_config == config) {
+        return Ok(config_path);
+    }
+
+    // written to a temp file and renamed into place, since other processes may be setting up the same directory
+    let fname = config_path.display().to_string();
+    let mut tmp_file = NamedTempFile::new_in(dir).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+    tmp_file.write_all(config.as_bytes()).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+    tmp_file.persist(&config_path).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err: err.error })?;
+
+    return Ok(config_path);
+}
+
+/// Creates `dir` and its missing parents so that only the current user can access them, or checks that an existing `dir` is like that.
+/// The wasm runtime loads the native code without checking it, so anyone who can write to it can run code on the host
+fn create_private_dir(dir: &Path) -> Result<()> {
+    let to_io_err = |err| UntRustedError::IoError { resource: format!("{:?}", dir), err };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir).map_err(to_io_err)?;
+
+        // not following symlinks, since the link could be swapped for a directory that someone else controls
+        let metadata = fs::symlink_metadata(dir).map_err(to_io_err)?;
+        // SAFETY: geteuid cannot fail, and does not touch memory
+        let euid = unsafe { libc::geteuid() };
+        if !metadata.is_dir() || metadata.uid() != euid || metadata.mode() & 0o077 != 0 {
+            return Err(UntRustedError::InsecureNativeCodeDir(format!("{:?}", dir)));
+        }
+    }
+
+    #[cfg(not(unix))]
+    fs::create_dir_all(dir).map_err(to_io_err)?;
+
+    return Ok(());
+}
+
+/// Total size of the files in the directory and its subdirectories, or 0 if it does not exist
+fn dir_size_bytes(dir: &Path) -> u64 {
+    let Ok(dir_entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    let mut size_bytes = 0;
+    for dir_entry in dir_entries.flatten() {
+        // files may be removed by another process in the meantime
+        let Ok(metadata) = dir_entry.metadata() else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            size_bytes += dir_size_bytes(&dir_entry.path());
+        } else {
+            size_bytes += metadata.len();
+        }
+    }
+
+    return size_bytes;
+}
+
+/// A directory of compiled projects, stored by their [`CompileCacheKey::hash`].
+///
+/// Multiple processes can share the same directory: writes are atomic, and an advisory lock file keeps eviction from racing with reads.
+/// Entries are evicted least-recently-used first, once the directory goes over the size limit or an entry goes over the age limit
+#[derive(Clone, Debug)]
+pub struct CompileCache {
+    dir: PathBuf,
+    max_size_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    counters: Arc<CompileCacheCounters>,
+}
+
+#[derive(Debug, Default)]
+struct CompileCacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompileCacheStats {
+    pub num_entries: usize,
+    pub total_bytes: u64,
+    /// The counters below are only for this `CompileCache` (and its clones), not for every process using the directory
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct CompileCacheEntry {
+    path: PathBuf,
+    /// size of the file along with its native code
+    size_bytes: u64,
+    last_used: SystemTime,
+}
+
+impl CompileCache {
+    /// Creates the cache directory if it does not exist yet, and removes the temp files of writers that crashed
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref()).map_err(|err| UntRustedError::IoError {
+            resource: format!("{:?}", dir.as_ref()),
+            err,
+        })?;
+
+        let compile_cache = Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_size_bytes: None,
+            max_age: None,
+            counters: Arc::new(CompileCacheCounters::default()),
+        };
+
+        {
+            let _lock = compile_cache.lock(true)?;
+            compile_cache.remove_orphaned_temp_files();
+        }
+
+        return Ok(compile_cache);
+    }
+
+    /// Evicts the least recently used entries once the total size of the cache goes over `num_bytes`
+    pub fn with_max_size_bytes(mut self, num_bytes: u64) -> Self {
+        self.max_size_bytes = Some(num_bytes);
+        self
+    }
+
+    /// Evicts entries that have not been used for longer than `max_age`
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, project_hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", project_hash, CACHE_FILE_EXTENSION))
+    }
+
+    fn lock(&self, exclusive: bool) -> Result<File> {
+        return lock_dir(&self.dir, exclusive);
+    }
+
+    pub(crate) fn load(&self, cache_key: &CompileCacheKey) -> Result<CompiledUntrustedRustProject> {
+        let entry_path = self.entry_path(&cache_key.hash());
+
+        let res = {
+            let _lock = self.lock(false)?;
+            read_compiled_project(&entry_path)
+        };
+
+        let compiled_project = match res {
+            Ok(compiled_project) if compiled_project.cache_key == *cache_key => compiled_project,
+            Ok(compiled_project) => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return Err(UntRustedError::CachedHashMismatch(compiled_project.cache_key.diff(cache_key)));
+            },
+            Err(err) => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            },
+        };
+
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+
+        // bump the modified time, since that is what eviction goes by
+        if let Err(err) = File::options().write(true).open(&entry_path).and_then(|file| file.set_modified(SystemTime::now())) {
+            warn!("failed to update last used time of {:?}: {}", entry_path, err);
+        }
+
+        return Ok(compiled_project);
+    }
+
+    pub(crate) fn save(&self, compiled_project: &CompiledUntrustedRustProject) -> Result<()> {
+        let entry_path = self.entry_path(&compiled_project.project_hash);
+
+        {
+            let _lock = self.lock(false)?;
+            write_compiled_project(&entry_path, compiled_project)?;
+        }
+
+        self.evict()?;
+
+        return Ok(());
+    }
+
+    fn entries(&self) -> Result<Vec<CompileCacheEntry>> {
+        let dir_entries = fs::read_dir(&self.dir).map_err(|err| UntRustedError::IoError {
+            resource: format!("{:?}", self.dir),
+            err,
+        })?;
+
+        let mut entries = Vec::new();
+        for dir_entry in dir_entries {
+            let dir_entry = dir_entry.map_err(|err| UntRustedError::IoError {
+                resource: format!("{:?}", self.dir),
+                err,
+            })?;
+
+            let path = dir_entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(CACHE_FILE_EXTENSION) {
+                continue;
+            }
+
+            // the entry may have been evicted by another process in the meantime
+            let Ok(metadata) = dir_entry.metadata() else {
+                continue;
+            };
+
+            let native_code_bytes = self.native_code_dir(&path).map(|native_code_dir| dir_size_bytes(&native_code_dir)).unwrap_or(0);
+            entries.push(CompileCacheEntry {
+                path,
+                size_bytes: metadata.len() + native_code_bytes,
+                last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+
+        return Ok(entries);
+    }
+
+    /// The native code directory of the entry with the given file, named after its project hash
+    fn native_code_dir(&self, entry_path: &Path) -> Option<PathBuf> {
+        return entry_path.file_stem().map(|project_hash| self.dir.join(NATIVE_CODE_DIR_NAME).join(project_hash));
+    }
+
+    /// Removes the entry along with its native code. Returns whether the entry was removed
+    fn remove_entry(&self, entry: &CompileCacheEntry) -> bool {
+        if let Err(err) = fs::remove_file(&entry.path) {
+            warn!("failed to remove {:?} from the compile cache: {}", entry.path, err);
+            return false;
+        }
+
+        if let Some(native_code_dir) = self.native_code_dir(&entry.path) {
+            if let Err(err) = fs::remove_dir_all(&native_code_dir) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("failed to remove the native code in {:?}: {}", native_code_dir, err);
+                }
+            }
+        }
+
+        return true;
+    }
+
+    /// Removes the temp files that writers left behind when they crashed before renaming them into place.
+    /// Must be called with the exclusive lock held, since every writer holds the shared lock until its temp file is renamed
+    fn remove_orphaned_temp_files(&self) {
+        let Ok(dir_entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+
+        for dir_entry in dir_entries.flatten() {
+            if !dir_entry.file_name().to_string_lossy().starts_with(TEMP_FILE_PREFIX) {
+                continue;
+            }
+
+            debug!("removing orphaned temp file {:?} from the compile cache", dir_entry.path());
+
+            if let Err(err) = fs::remove_file(dir_entry.path()) {
+                warn!("failed to remove orphaned temp file {:?} from the compile cache: {}", dir_entry.path(), err);
+            }
+        }
+    }
+
+    /// Removes entries that are over the age limit, and then removes the least recently used entries until the cache fits in the size limit.
+    /// Returns the number of removed entries
+    pub fn evict(&self) -> Result<usize> {
+        if self.max_size_bytes.is_none() && self.max_age.is_none() {
+            return Ok(0);
+        }
+
+        let _lock = self.lock(true)?;
+        self.remove_orphaned_temp_files();
+
+        let mut entries = self.entries()?;
+        // oldest first
+        entries.sort_by_key(|entry| entry.last_used);
+
+        let now = SystemTime::now();
+        let mut total_bytes: u64 = entries.iter().map(|entry| entry.size_bytes).sum();
+        let mut num_evicted: usize = 0;
+
+        for entry in &entries {
+            let is_expired = match self.max_age {
+                Some(max_age) => now.duration_since(entry.last_used).map(|age| age > max_age).unwrap_or(false),
+                None => false,
+            };
+            let is_over_size = match self.max_size_bytes {
+                Some(max_size_bytes) => total_bytes > max_size_bytes,
+                None => false,
+            };
+
+            if !is_expired && !is_over_size {
+                continue;
+            }
+
+            debug!("evicting {:?} from the compile cache", entry.path);
+
+            // an entry that could not be removed still takes up space, so it does not count towards the size limit either
+            if self.remove_entry(entry) {
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+                total_bytes -= entry.size_bytes;
+                num_evicted += 1;
+            }
+        }
+
+        return Ok(num_evicted);
+    }
+
+    /// Removes every entry from the cache, along with their native code. These do not count as evictions in [`Self::stats`]
+    pub fn purge(&self) -> Result<()> {
+        let _lock = self.lock(true)?;
+        self.remove_orphaned_temp_files();
+
+        for entry in self.entries()? {
+            self.remove_entry(&entry);
+        }
+
+        return Ok(());
+    }
+
+    pub fn stats(&self) -> Result<CompileCacheStats> {
+        let entries = {
+            let _lock = self.lock(false)?;
+            self.entries()?
+        };
+
+        return Ok(CompileCacheStats {
+            num_entries: entries.len(),
+            total_bytes: entries.iter().map(|entry| entry.size_bytes).sum(),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+        });
+    }
+}
+
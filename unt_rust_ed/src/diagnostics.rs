@@ -0,0 +1,347 @@
+use std::fmt;
+
+use proc_macro2::{Delimiter, Group, Span, TokenStream, TokenTree};
+use serde::{Serialize, Deserialize};
+use syn::__private::ToTokens;
+
+use crate::policy::SourceSpan;
+
+/// Name of the generated library target, diagnostics for anything else (dependencies) are ignored
+const CARGO_TARGET_NAME: &str = "test_wasm";
+const LIB_RS_FILE_NAME: &str = "src/lib.rs";
+
+/// How far ahead to look in the generated code when it has drifted from the ast (see [`SourceMap::new`])
+const MAX_RESYNC_LOOKAHEAD: usize = 16;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DiagnosticLevel {
+    Error,
+    Warning,
+    Note,
+    Help,
+}
+
+impl fmt::Display for DiagnosticLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            DiagnosticLevel::Error => "error",
+            DiagnosticLevel::Warning => "warning",
+            DiagnosticLevel::Note => "note",
+            DiagnosticLevel::Help => "help",
+        };
+        write!(f, "{}", level)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DiagnosticSpan {
+    /// Location in the untrusted rust code
+    pub span: SourceSpan,
+    pub is_primary: bool,
+    pub label: Option<String>,
+}
+
+/// A compiler error or warning, located in the untrusted rust code.
+/// Spans that only cover generated code (export wrappers, host typedefs, ...) are left out
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub level: DiagnosticLevel,
+    /// Error code, e.g. `E0308`
+    pub code: Option<String>,
+    pub message: String,
+    pub spans: Vec<DiagnosticSpan>,
+    /// Notes and help attached to this diagnostic
+    pub children: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    pub fn primary_span(&self) -> Option<&SourceSpan> {
+        self.spans.iter().find(|span| span.is_primary).map(|span| &span.span)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.level)?;
+        if let Some(code) = &self.code {
+            write!(f, "[{}]", code)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(span) = self.primary_span() {
+            write!(f, " at {}", span)?;
+        }
+        Ok(())
+    }
+}
+
+/// (line, column) with lines starting at 1 and columns starting at 0, same as [`proc_macro2::LineColumn`]
+type Position = (usize, usize);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MappedToken {
+    start: Position,
+    end: Position,
+    original: Option<SourceSpan>,
+}
+
+/// Maps locations in the generated `lib.rs` back to the untrusted rust code
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SourceMap {
+    tokens: Vec<MappedToken>,
+}
+
+impl SourceMap {
+    /// `ast` is the final ast that was unparsed into `generated_code`. Its tokens still carry their spans from parsing the untrusted code,
+    /// while generated tokens have call site spans (and the appended typedefs come after `original_len`).
+    ///
+    /// `prettyplease` does not report where it put each token, so the generated code is lexed again and lined up with the ast tokens.
+    /// They only drift apart where `prettyplease` adds or drops separators
+    pub(crate) fn new(ast: &syn::File, generated_code: &str, original_len: usize) -> syn::Result<Self> {
+        let mut ast_tokens = Vec::new();
+        flatten_tokens(ast.to_token_stream(), &mut ast_tokens);
+
+        let generated_token_stream: TokenStream = generated_code.parse()?;
+        let mut generated_tokens = Vec::new();
+        flatten_tokens(generated_token_stream, &mut generated_tokens);
+
+        let mut tokens: Vec<MappedToken> = generated_tokens.iter()
+            .map(|(_, span)| MappedToken {
+                start: (span.start().line, span.start().column),
+                end: (span.end().line, span.end().column),
+                original: None,
+            })
+            .collect();
+
+        let mut ast_idx = 0;
+        let mut generated_idx = 0;
+        while ast_idx < ast_tokens.len() && generated_idx < generated_tokens.len() {
+            let (ast_text, ast_span) = &ast_tokens[ast_idx];
+            let (generated_text, _) = &generated_tokens[generated_idx];
+
+            if ast_text == generated_text {
+                let byte_range = ast_span.byte_range();
+                if !byte_range.is_empty() && byte_range.end <= original_len {
+                    tokens[generated_idx].original = Some(SourceSpan::from(*ast_span));
+                }
+                ast_idx += 1;
+                generated_idx += 1;
+            } else if is_separator(generated_text) {
+                generated_idx += 1;
+            } else if is_separator(ast_text) {
+                ast_idx += 1;
+            } else {
+                let resync = generated_tokens[generated_idx..].iter()
+                    .take(MAX_RESYNC_LOOKAHEAD)
+                    .position(|(text, _)| text == ast_text);
+
+                match resync {
+                    Some(offset) => generated_idx += offset,
+                    None => ast_idx += 1,
+                }
+            }
+        }
+
+        return Ok(Self {
+            tokens,
+        });
+    }
+
+    /// Finds the part of the untrusted rust code that the generated tokens between `start` and `end` came from
+    pub(crate) fn map(&self, start: Position, end: Position) -> Option<SourceSpan> {
+        let mut mapped: Option<SourceSpan> = None;
+
+        for token in &self.tokens {
+            let overlaps = if start == end {
+                token.start <= start && start < token.end
+            } else {
+                token.start < end && start < token.end
+            };
+
+            let Some(original) = token.original.filter(|_| overlaps) else {
+                continue;
+            };
+
+            mapped = Some(match mapped {
+                None => original,
+                Some(mapped) => SourceSpan {
+                    start_line: mapped.start_line,
+                    start_column: mapped.start_column,
+                    end_line: original.end_line,
+                    end_column: original.end_column,
+                },
+            });
+        }
+
+        return mapped;
+    }
+}
+
+/// Parses code that is generated from a string, rather than taken from the untrusted code.
+/// Its spans would otherwise be mistaken for locations in the untrusted code, so they are all replaced with the call site
+pub(crate) fn parse_str_as_generated<T: syn::parse::Parse>(code: &str) -> syn::Result<T> {
+    let tokens: TokenStream = code.parse()?;
+    return syn::parse2(respan(tokens, Span::call_site()));
+}
+
+fn respan(tokens: TokenStream, span: Span) -> TokenStream {
+    tokens.into_iter()
+        .map(|mut token| {
+            if let TokenTree::Group(group) = &token {
+                let mut new_group = Group::new(group.delimiter(), respan(group.stream(), span));
+                new_group.set_span(span);
+                token = TokenTree::Group(new_group);
+            } else {
+                token.set_span(span);
+            }
+            token
+        })
+        .collect()
+}
+
+fn is_separator(text: &str) -> bool {
+    text == "," || text == ";"
+}
+
+/// Flattens the tokens, with delimiters as their own tokens. Literals are compared by kind only,
+/// since `prettyplease` prints doc attributes (`#[doc = r" .."]`) as doc comments
+fn flatten_tokens(tokens: TokenStream, flattened: &mut Vec<(String, Span)>) {
+    for token in tokens {
+        match token {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                flattened.push((open.to_string(), group.span_open()));
+                flatten_tokens(group.stream(), flattened);
+                flattened.push((close.to_string(), group.span_close()));
+            },
+            TokenTree::Literal(literal) => flattened.push(("<literal>".to_string(), literal.span())),
+            TokenTree::Ident(ident) => flattened.push((ident.to_string(), ident.span())),
+            TokenTree::Punct(punct) => flattened.push((punct.as_char().to_string(), punct.span())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    target: Option<CargoTarget>,
+    message: Option<RustcDiagnostic>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct RustcDiagnostic {
+    message: String,
+    code: Option<RustcCode>,
+    level: String,
+    spans: Vec<RustcSpan>,
+    children: Vec<RustcDiagnostic>,
+}
+
+#[derive(Deserialize)]
+struct RustcCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct RustcSpan {
+    file_name: String,
+    line_start: usize,
+    line_end: usize,
+    /// starts at 1
+    column_start: usize,
+    column_end: usize,
+    is_primary: bool,
+    label: Option<String>,
+    expansion: Option<Box<RustcExpansion>>,
+}
+
+#[derive(Deserialize)]
+struct RustcExpansion {
+    span: RustcSpan,
+}
+
+/// Parses the output of `cargo build --message-format=json` into diagnostics located in the untrusted rust code
+pub(crate) fn parse_cargo_messages(stdout: &str, source_map: &SourceMap) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for line in stdout.lines() {
+        let Ok(cargo_message) = serde_json::from_str::<CargoMessage>(line) else {
+            continue;
+        };
+
+        if cargo_message.reason != "compiler-message" {
+            continue;
+        }
+
+        if cargo_message.target.map(|target| target.name) != Some(CARGO_TARGET_NAME.to_string()) {
+            continue;
+        }
+
+        if let Some(diagnostic) = cargo_message.message.and_then(|message| convert_diagnostic(message, source_map, true)) {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    return diagnostics;
+}
+
+fn convert_diagnostic(diagnostic: RustcDiagnostic, source_map: &SourceMap, is_top_level: bool) -> Option<Diagnostic> {
+    let level = match diagnostic.level.as_str() {
+        "warning" => DiagnosticLevel::Warning,
+        "note" => DiagnosticLevel::Note,
+        "help" => DiagnosticLevel::Help,
+        // e.g. "For more information about this error, try `rustc --explain`"
+        "failure-note" => return None,
+        level if level.starts_with("error") => DiagnosticLevel::Error,
+        _ => DiagnosticLevel::Note,
+    };
+
+    if level == DiagnosticLevel::Error && diagnostic.spans.is_empty() && diagnostic.message.starts_with("aborting due to") {
+        return None;
+    }
+
+    let had_spans = !diagnostic.spans.is_empty();
+    let spans: Vec<DiagnosticSpan> = diagnostic.spans.into_iter()
+        .filter_map(|span| convert_span(span, source_map))
+        .collect();
+
+    // only about generated code. Errors are kept regardless, so that a failed build always explains itself
+    if had_spans && spans.is_empty() && !(is_top_level && level == DiagnosticLevel::Error) {
+        return None;
+    }
+
+    return Some(Diagnostic {
+        level,
+        code: diagnostic.code.map(|code| code.code),
+        message: diagnostic.message,
+        spans,
+        children: diagnostic.children.into_iter()
+            .filter_map(|child| convert_diagnostic(child, source_map, false))
+            .collect(),
+    });
+}
+
+fn convert_span(span: RustcSpan, source_map: &SourceMap) -> Option<DiagnosticSpan> {
+    // spans inside macros from other crates point at their definition, so use where they were called from
+    if span.file_name != LIB_RS_FILE_NAME {
+        return span.expansion.and_then(|expansion| convert_span(expansion.span, source_map));
+    }
+
+    let start = (span.line_start, span.column_start.saturating_sub(1));
+    let end = (span.line_end, span.column_end.saturating_sub(1));
+
+    return source_map.map(start, end).map(|mapped| DiagnosticSpan {
+        span: mapped,
+        is_primary: span.is_primary,
+        label: span.label,
+    });
+}
@@ -1,6 +1,11 @@
 
+use std::fmt;
+
 use thiserror::Error;
 
+use crate::diagnostics::Diagnostic;
+use crate::policy::{SourceSpan, Violation};
+
 #[derive(Error, Debug)]
 pub enum UntRustedError {
     #[error("Io Error for {resource}: {err}")]
@@ -16,10 +21,69 @@ pub enum UntRustedError {
     SerdeDeserialize(String, flexbuffers::DeserializationError),
     #[error("Serde error during serialize (resource={0}): {1}")]
     SerdeSerialize(String, flexbuffers::SerializationError),
+    #[error("Serde json error (resource={0}): {1}")]
+    SerdeJson(String, serde_json::Error),
     #[error("Syn error: {0}")]
     Syn(syn::Error),
+    #[error("The untrusted code violates the policy: {}", .0.iter().map(Violation::to_string).collect::<Vec<String>>().join(", "))]
+    PolicyViolation(Vec<Violation>),
+    #[error("The untrusted code does not implement {trait_name} correctly: {}", .problems.join(", "))]
+    InvalidTraitImpl {
+        trait_name: String,
+        problems: Vec<String>,
+    },
+    #[error("The untrusted code does not match the declared exports: {}", .0.join(", "))]
+    InvalidExports(Vec<String>),
+    #[error("The compiled project does not export {0}")]
+    MissingExport(String),
+    /// `location` is `None` if the panic was not in the untrusted code, e.g. in a dependency
+    #[error("The guest function {function} panicked{}: {message}", .location.map(|location| format!(" at {}", location)).unwrap_or_default())]
+    GuestPanic {
+        function: String,
+        message: String,
+        location: Option<SourceSpan>,
+    },
+    #[error("The guest function {function} trapped: {trap}")]
+    GuestTrap {
+        function: String,
+        trap: TrapKind,
+    },
+    /// The untrusted code returned `Err`, with the error as json (see [`crate::Container::call_result`])
+    #[error("The guest function {function} returned an error: {payload}")]
+    GuestError {
+        function: String,
+        payload: serde_json::Value,
+    },
+    #[error("The compiled wasm has no export metadata section")]
+    MissingExportMetadata,
+    #[error("The host type {host_type} does not match {guest_type} in the exported function {fn_name}")]
+    TypedFuncMismatch {
+        fn_name: String,
+        host_type: String,
+        guest_type: String,
+    },
+    #[error("The exported function {fn_name} passes {guest_type} in extism's own encoding, which a typed function cannot check")]
+    UnsupportedTypedFuncType {
+        fn_name: String,
+        guest_type: String,
+    },
+    #[error("The compiled project does not export {trait_name}::{method_name}")]
+    MissingTraitMethod {
+        trait_name: String,
+        method_name: String,
+    },
     #[error("Missing target {0}, can install using `rustup target add {0}`")]
     MissingCargoTargetInstallation(String),
+    #[error("The build sandbox cannot be used: {0}")]
+    InvalidBuildSandbox(String),
+    #[error("The native code directory {0} has to be a directory that is owned by the current user, and that no one else can access")]
+    InsecureNativeCodeDir(String),
+    #[error("The pool options cannot be used: {0}")]
+    InvalidPoolOptions(String),
+    #[error("cargo build did not finish within {0:?}")]
+    CompileTimeout(std::time::Duration),
+    #[error("The untrusted code failed to compile: {}", .0.iter().map(Diagnostic::to_string).collect::<Vec<String>>().join(", "))]
+    CompileError(Vec<Diagnostic>),
     #[error("Hit unknown cargo build error.\nSTDOUT:\n{0}\nSTDERR:\n{1}")]
     UnknownCargoError(String, String),
     #[error("This PatType is not supported: {0}")]
@@ -28,10 +92,30 @@ pub enum UntRustedError {
     UnsupportedFnArg(String),
     #[error("This external function call ({0}) took too long to execute")]
     RuntimeExceededTimeout(String),
+    /// The call was stopped through a [`crate::CancelHandle`]
+    #[error("This external function call ({0}) was cancelled")]
+    Cancelled(String),
     #[error("This external function call ({0}) attempted to allocate too much memory")]
     RuntimeExceededMemory(String),
-    #[error("Cached compiled project hash did not match, so recompiling the project")]
-    CachedHashMismatch,
+    #[error("This external function call ({fn_name}) requested {requested} bytes of heap, but only {allowed} bytes are allowed")]
+    RuntimeExceededHeapLimit {
+        fn_name: String,
+        requested: usize,
+        allowed: usize,
+    },
+    #[error("This external function call ({fn_name}) returned {size} bytes, but only {allowed} bytes are allowed")]
+    RuntimeExceededOutputSize {
+        fn_name: String,
+        size: usize,
+        allowed: usize,
+    },
+    #[error("This external function call ({function}) ran out of fuel after using {consumed}")]
+    FuelExhausted {
+        function: String,
+        consumed: u64,
+    },
+    #[error("Cached compiled project hash did not match (changed: {0:?}), so recompiling the project")]
+    CachedHashMismatch(Vec<&'static str>),
 }
 
 impl From<extism::Error> for UntRustedError {
@@ -47,3 +131,62 @@ impl From<syn::Error> for UntRustedError {
 }
 
 pub type Result<T> = std::result::Result<T, UntRustedError>;
+
+/// Why the wasm runtime stopped a guest function
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrapKind {
+    /// An `unreachable` instruction, e.g. from `std::process::abort`
+    Unreachable,
+    IntegerDivisionByZero,
+    IntegerOverflow,
+    /// The wasm call stack ran out
+    StackOverflow,
+    /// Includes running out of the rust stack, since it is kept in linear memory
+    MemoryOutOfBounds,
+    HeapMisaligned,
+    TableOutOfBounds,
+    IndirectCallToNull,
+    BadSignature,
+    BadConversionToInteger,
+    OutOfFuel,
+    Other,
+}
+
+impl From<wasmtime::Trap> for TrapKind {
+    fn from(trap: wasmtime::Trap) -> Self {
+        match trap {
+            wasmtime::Trap::UnreachableCodeReached => TrapKind::Unreachable,
+            wasmtime::Trap::IntegerDivisionByZero => TrapKind::IntegerDivisionByZero,
+            wasmtime::Trap::IntegerOverflow => TrapKind::IntegerOverflow,
+            wasmtime::Trap::StackOverflow => TrapKind::StackOverflow,
+            wasmtime::Trap::MemoryOutOfBounds => TrapKind::MemoryOutOfBounds,
+            wasmtime::Trap::HeapMisaligned => TrapKind::HeapMisaligned,
+            wasmtime::Trap::TableOutOfBounds => TrapKind::TableOutOfBounds,
+            wasmtime::Trap::IndirectCallToNull => TrapKind::IndirectCallToNull,
+            wasmtime::Trap::BadSignature => TrapKind::BadSignature,
+            wasmtime::Trap::BadConversionToInteger => TrapKind::BadConversionToInteger,
+            wasmtime::Trap::OutOfFuel => TrapKind::OutOfFuel,
+            _ => TrapKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let trap = match self {
+            TrapKind::Unreachable => "unreachable code",
+            TrapKind::IntegerDivisionByZero => "integer divide by zero",
+            TrapKind::IntegerOverflow => "integer overflow",
+            TrapKind::StackOverflow => "stack overflow",
+            TrapKind::MemoryOutOfBounds => "out of bounds memory access",
+            TrapKind::HeapMisaligned => "misaligned memory access",
+            TrapKind::TableOutOfBounds => "out of bounds table access",
+            TrapKind::IndirectCallToNull => "indirect call to null",
+            TrapKind::BadSignature => "indirect call signature mismatch",
+            TrapKind::BadConversionToInteger => "invalid conversion to integer",
+            TrapKind::OutOfFuel => "out of fuel",
+            TrapKind::Other => "other trap",
+        };
+        write!(f, "{}", trap)
+    }
+}
@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
+
+use proc_macro2::{Literal, Span, TokenStream, TokenTree};
+use quote::quote;
+use serde::{Serialize, Deserialize};
+use serde::de::{DeserializeOwned, Error as _};
+use serde_json::Value;
+use syn::__private::ToTokens;
+use syn::visit::Visit;
+
+use crate::{Container, ExportedHostType};
+use crate::diagnostics::parse_str_as_generated;
+use crate::error::*;
+
+/// How a value crosses into or out of the guest
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Encoding {
+    /// Extism's own byte encoding, e.g. little endian numbers and utf8 strings
+    Raw,
+    Json,
+    /// A json tuple of all the params (only used for inputs)
+    JsonTuple,
+}
+
+/// Name of the wasm custom section that lists the exported functions
+pub const EXPORTS_SECTION_NAME: &str = "unt_rust_ed_exports";
+
+/// A function exported by a compiled project, with its types as they are written in the untrusted code
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExportedFunction {
+    /// Name to call it with, which may have module prefixes (e.g. `foo::exported_fn`)
+    pub name: String,
+    /// Name of the wasm export, e.g. `__exported_fn` or `foo__exported_fn`
+    pub mangled_name: String,
+    pub params: Vec<String>,
+    /// `None` for functions that do not return anything. For a `Result<T, E>`, this is `T`
+    pub return_type: Option<String>,
+    /// The `E` of a `Result<T, E>`, which is returned as [`UntRustedError::GuestError`]
+    pub error_type: Option<String>,
+    /// Exported host types used by the params or return type
+    pub host_types: Vec<String>,
+    /// Doc comments of the function, one line each
+    pub docs: Vec<String>,
+    pub input_encoding: Encoding,
+    pub output_encoding: Encoding,
+}
+
+impl ExportedFunction {
+    pub(crate) fn new(name: String, mangled_name: String, attrs: &[syn::Attribute], inputs: &syn::punctuated::Punctuated<syn::FnArg, syn::Token![,]>,
+                      output: &syn::ReturnType, input_encoding: Encoding, output_encoding: Encoding) -> Self {
+        Self {
+            name,
+            mangled_name,
+            params: inputs.iter()
+                .filter_map(|input| match input {
+                    syn::FnArg::Typed(pat_type) => Some(type_to_string(&pat_type.ty)),
+                    syn::FnArg::Receiver(_) => None,
+                })
+                .collect(),
+            return_type: match output {
+                syn::ReturnType::Default => None,
+                syn::ReturnType::Type(_, ty) => Some(type_to_string(ty)),
+            },
+            error_type: None,
+            host_types: Vec::new(),
+            docs: attrs.iter()
+                .filter_map(|attr| match &attr.meta {
+                    syn::Meta::NameValue(name_value) if name_value.path.is_ident("doc") => match &name_value.value {
+                        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(doc), .. }) => Some(doc.value().trim().to_string()),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            input_encoding,
+            output_encoding,
+        }
+    }
+
+    /// Fills in [`Self::host_types`] from the param and return types
+    pub(crate) fn find_host_types(&mut self, host_typenames: &HashSet<String>) {
+        let mut host_types: Vec<String> = self.params.iter()
+            .chain(self.return_type.iter())
+            .chain(self.error_type.iter())
+            .flat_map(|type_name| type_name.split(|c: char| !(c.is_alphanumeric() || c == '_')))
+            .filter(|word| host_typenames.contains(*word))
+            .map(String::from)
+            .collect();
+        host_types.sort();
+        host_types.dedup();
+        self.host_types = host_types;
+    }
+
+    /// The type that the host passes in: `()`, the only param, or a tuple of all the params
+    pub fn input_type(&self) -> String {
+        match (self.input_encoding, self.params.as_slice()) {
+            (Encoding::JsonTuple, [param]) => format!("({},)", param),
+            (Encoding::JsonTuple, params) => format!("({})", params.join(", ")),
+            (_, [param]) => param.clone(),
+            (_, _) => "()".to_string(),
+        }
+    }
+
+    /// The type that the host gets back
+    pub fn output_type(&self) -> String {
+        self.return_type.clone().unwrap_or_else(|| "()".to_string())
+    }
+
+    /// The return type as written in the untrusted code, including the `Result`
+    fn declared_output_type(&self) -> String {
+        match &self.error_type {
+            Some(error_type) => format!("Result<{}, {}>", self.output_type(), error_type),
+            None => self.output_type(),
+        }
+    }
+}
+
+/// Creates a static in the guest holding the exports as json, which ends up as a custom section in the wasm
+pub(crate) fn create_metadata_item(exports: &[ExportedFunction]) -> Result<syn::Item> {
+    let metadata = serde_json::to_vec(exports).map_err(|err| UntRustedError::SerdeJson(EXPORTS_SECTION_NAME.to_string(), err))?;
+
+    let len = metadata.len();
+    let mut bytes = Literal::byte_string(&metadata);
+    bytes.set_span(Span::call_site());
+
+    return Ok(syn::parse2(quote! {
+        #[used]
+        #[link_section = #EXPORTS_SECTION_NAME]
+        static __UNT_RUST_ED_EXPORTS: [u8; #len] = *#bytes;
+    })?);
+}
+
+/// Reads the exports back out of the custom section of the wasm
+pub(crate) fn read_metadata(wasm: &[u8]) -> Result<Vec<ExportedFunction>> {
+    let section = find_custom_section(wasm, EXPORTS_SECTION_NAME).ok_or(UntRustedError::MissingExportMetadata)?;
+    return serde_json::from_slice(section).map_err(|err| UntRustedError::SerdeJson(EXPORTS_SECTION_NAME.to_string(), err));
+}
+
+/// An entry point that the host expects, see [`crate::UntrustedRustProject::with_required_export`]
+#[derive(Clone, Debug)]
+pub(crate) struct DeclaredExport {
+    /// e.g. `fn(Inputs) -> Outputs`
+    pub signature: String,
+    pub required: bool,
+}
+
+/// Checks the exports against the declared entry points, returning a description of every problem.
+/// Types are compared by their canonical names, so `&str` matches `String`
+pub(crate) fn check_declared_exports(declared_exports: &[(&String, &DeclaredExport)], exports: &[ExportedFunction]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (name, declared_export) in declared_exports {
+        let Some(export) = exports.iter().find(|export| &export.name == *name) else {
+            if declared_export.required {
+                problems.push(format!("missing function {}", name));
+            }
+            continue;
+        };
+
+        let signature: syn::TypeBareFn = match parse_str_as_generated(&declared_export.signature) {
+            Ok(signature) => signature,
+            Err(err) => {
+                problems.push(format!("the declared signature of {} is not a fn type ({}): {}", name, declared_export.signature, err));
+                continue;
+            },
+        };
+
+        let expected_params: Vec<String> = signature.inputs.iter().map(|input| type_to_string(&input.ty)).collect();
+        if expected_params.len() != export.params.len() {
+            problems.push(format!("{} takes {} params, expected {}", name, export.params.len(), expected_params.len()));
+        } else {
+            for (idx, (param, expected_param)) in export.params.iter().zip(&expected_params).enumerate() {
+                if canonical_type_name(param) != canonical_type_name(expected_param) {
+                    problems.push(format!("param {} of {} is {}, expected {}", idx, name, param, expected_param));
+                }
+            }
+        }
+
+        let expected_output = match &signature.output {
+            syn::ReturnType::Default => "()".to_string(),
+            syn::ReturnType::Type(_, ty) => type_to_string(ty),
+        };
+        if canonical_type_name(&export.declared_output_type()) != canonical_type_name(&expected_output) {
+            problems.push(format!("{} returns {}, expected {}", name, export.declared_output_type(), expected_output));
+        }
+    }
+
+    return problems;
+}
+
+/// Walks the sections of a wasm module, see https://webassembly.github.io/spec/core/binary/modules.html#sections
+fn find_custom_section<'a>(wasm: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    const CUSTOM_SECTION_ID: u8 = 0;
+
+    // skip the magic number and version
+    let mut rest = wasm.get(8..)?;
+    while let Some((&section_id, after_id)) = rest.split_first() {
+        let (section_len, after_len) = read_leb128_u32(after_id)?;
+        let section = after_len.get(..section_len as usize)?;
+        rest = &after_len[section_len as usize..];
+
+        if section_id == CUSTOM_SECTION_ID {
+            let (name_len, after_name_len) = read_leb128_u32(section)?;
+            let section_name = after_name_len.get(..name_len as usize)?;
+            if section_name == name.as_bytes() {
+                return Some(&after_name_len[name_len as usize..]);
+            }
+        }
+    }
+
+    return None;
+}
+
+fn read_leb128_u32(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    let mut value: u32 = 0;
+    for (idx, byte) in bytes.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as u32) << (7 * idx);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[idx + 1..]));
+        }
+    }
+    return None;
+}
+
+/// A handle to an exported function whose types have been checked against `I` and `O` (see [`Container::typed_func`])
+pub struct TypedFunc<'c, I, O> {
+    container: &'c mut Container,
+    export: ExportedFunction,
+    _types: PhantomData<fn(I) -> O>,
+}
+
+impl<'c, I: Serialize + GuestTypeName, O: DeserializeOwned + GuestTypeName> TypedFunc<'c, I, O> {
+    pub(crate) fn new(container: &'c mut Container, export: ExportedFunction) -> Result<Self> {
+        let checks = [
+            (I::guest_type_name(), export.input_type(), export.input_encoding),
+            (O::guest_type_name(), export.output_type(), export.output_encoding),
+        ];
+
+        for (host_type, guest_type, encoding) in checks {
+            let canonical_guest_type = canonical_type_name(&guest_type);
+            if encoding == Encoding::Raw && !RAW_TYPES.contains(&canonical_guest_type.as_str()) {
+                return Err(UntRustedError::UnsupportedTypedFuncType {
+                    fn_name: export.name.clone(),
+                    guest_type,
+                });
+            }
+
+            if canonical_type_name(&host_type) != canonical_guest_type {
+                return Err(UntRustedError::TypedFuncMismatch {
+                    fn_name: export.name.clone(),
+                    host_type,
+                    guest_type,
+                });
+            }
+        }
+
+        return Ok(Self {
+            container,
+            export,
+            _types: PhantomData,
+        });
+    }
+
+    pub fn export(&self) -> &ExportedFunction {
+        &self.export
+    }
+
+    pub fn call(&mut self, input: I) -> Result<O> {
+        let to_json_err = |err| UntRustedError::SerdeJson(self.export.name.clone(), err);
+
+        let input_bytes = match self.export.input_encoding {
+            Encoding::Raw => serde_json::to_value(&input)
+                .and_then(|value| encode_raw(value, &canonical_type_name(&self.export.input_type())))
+                .map_err(to_json_err)?,
+            Encoding::Json | Encoding::JsonTuple => serde_json::to_vec(&input).map_err(to_json_err)?,
+        };
+
+        let output_bytes: Vec<u8> = self.container.call(&self.export.name, input_bytes)?;
+
+        let to_json_err = |err| UntRustedError::SerdeJson(self.export.name.clone(), err);
+        return match self.export.output_encoding {
+            Encoding::Raw => decode_raw(&output_bytes, &canonical_type_name(&self.export.output_type()))
+                .and_then(serde_json::from_value)
+                .map_err(to_json_err),
+            Encoding::Json | Encoding::JsonTuple => serde_json::from_slice(&output_bytes).map_err(to_json_err),
+        };
+    }
+}
+
+/// The name of a type as it is written in the untrusted code, so that [`TypedFunc`] can check it against the exported signature.
+/// Implemented for the std types that can cross into the guest, and for every [`ExportedHostType`] through its registered name
+pub trait GuestTypeName {
+    fn guest_type_name() -> String;
+}
+
+impl<T: ExportedHostType> GuestTypeName for T {
+    fn guest_type_name() -> String {
+        T::typename().to_string()
+    }
+}
+
+macro_rules! impl_guest_type_name {
+    ($($ty:ty),*) => {
+        $(
+            impl GuestTypeName for $ty {
+                fn guest_type_name() -> String {
+                    stringify!($ty).to_string()
+                }
+            }
+        )*
+    };
+}
+
+impl_guest_type_name!(bool, char, i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64, String);
+
+macro_rules! impl_guest_type_name_for_generic {
+    ($($ty:ident<$($param:ident),+>),*) => {
+        $(
+            impl<$($param: GuestTypeName),+> GuestTypeName for $ty<$($param),+> {
+                fn guest_type_name() -> String {
+                    let params: Vec<String> = vec![$($param::guest_type_name()),+];
+                    format!("{}<{}>", stringify!($ty), params.join(", "))
+                }
+            }
+        )*
+    };
+}
+
+impl_guest_type_name_for_generic!(Vec<T>, Option<T>, HashSet<T>, BTreeSet<T>, HashMap<K, V>, BTreeMap<K, V>);
+
+macro_rules! impl_guest_type_name_for_tuple {
+    ($(($($param:ident),*)),*) => {
+        $(
+            impl<$($param: GuestTypeName),*> GuestTypeName for ($($param,)*) {
+                fn guest_type_name() -> String {
+                    let params: Vec<String> = vec![$($param::guest_type_name()),*];
+                    return match params.len() {
+                        1 => format!("({},)", params[0]),
+                        _ => format!("({})", params.join(", ")),
+                    };
+                }
+            }
+        )*
+    };
+}
+
+impl_guest_type_name_for_tuple!((), (A), (A, B), (A, B, C), (A, B, C, D), (A, B, C, D, E), (A, B, C, D, E, F), (A, B, C, D, E, F, G), (A, B, C, D, E, F, G, H));
+
+/// Types that extism can pass without json, in their canonical form (see [`canonical_type_name`])
+pub(crate) const RAW_TYPES: &[&str] = &["()", "String", "Vec<u8>", "i32", "i64", "u32", "u64", "f32", "f64"];
+
+fn encode_raw(value: Value, canonical_type: &str) -> serde_json::Result<Vec<u8>> {
+    let mismatch = || serde_json::Error::custom(format!("cannot encode {} as {}", value, canonical_type));
+
+    let bytes = match canonical_type {
+        "()" => Vec::new(),
+        "String" => value.as_str().ok_or_else(mismatch)?.as_bytes().to_vec(),
+        "Vec<u8>" => serde_json::from_value::<Vec<u8>>(value.clone())?,
+        "i32" => i32::try_from(value.as_i64().ok_or_else(mismatch)?).map_err(|_| mismatch())?.to_le_bytes().to_vec(),
+        "i64" => value.as_i64().ok_or_else(mismatch)?.to_le_bytes().to_vec(),
+        "u32" => u32::try_from(value.as_u64().ok_or_else(mismatch)?).map_err(|_| mismatch())?.to_le_bytes().to_vec(),
+        "u64" => value.as_u64().ok_or_else(mismatch)?.to_le_bytes().to_vec(),
+        "f32" => (value.as_f64().ok_or_else(mismatch)? as f32).to_le_bytes().to_vec(),
+        "f64" => value.as_f64().ok_or_else(mismatch)?.to_le_bytes().to_vec(),
+        _ => return Err(mismatch()),
+    };
+
+    return Ok(bytes);
+}
+
+fn decode_raw(bytes: &[u8], canonical_type: &str) -> serde_json::Result<Value> {
+    let mismatch = || serde_json::Error::custom(format!("cannot decode {} bytes as {}", bytes.len(), canonical_type));
+
+    let value = match canonical_type {
+        "()" => Value::Null,
+        "String" => Value::String(String::from_utf8(bytes.to_vec()).map_err(|_| mismatch())?),
+        "Vec<u8>" => serde_json::to_value(bytes)?,
+        "i32" => Value::from(i32::from_le_bytes(bytes.try_into().map_err(|_| mismatch())?)),
+        "i64" => Value::from(i64::from_le_bytes(bytes.try_into().map_err(|_| mismatch())?)),
+        "u32" => Value::from(u32::from_le_bytes(bytes.try_into().map_err(|_| mismatch())?)),
+        "u64" => Value::from(u64::from_le_bytes(bytes.try_into().map_err(|_| mismatch())?)),
+        "f32" => Value::from(f32::from_le_bytes(bytes.try_into().map_err(|_| mismatch())?)),
+        "f64" => Value::from(f64::from_le_bytes(bytes.try_into().map_err(|_| mismatch())?)),
+        _ => return Err(mismatch()),
+    };
+
+    return Ok(value);
+}
+
+/// True for types that extism passes as raw bytes, e.g. `String` or `i64`
+pub(crate) fn is_raw_type(ty: &syn::Type) -> bool {
+    return RAW_TYPES.contains(&canonical_type_name(&type_to_string(ty)).as_str());
+}
+
+/// How a param of the untrusted code is borrowed. The exported wrapper has to own it, and then borrows it for the call
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Borrow {
+    Owned,
+    Shared,
+    Mut,
+}
+
+/// The type that a param is deserialized as, e.g. `String` for `&str` and `Vec<T>` for `&[T]`.
+/// Only a top level borrow can be made owned, so a reference inside of another type (e.g. `Option<&str>`) is an [`UntRustedError::UnsupportedFnArg`]
+pub(crate) fn owned_type(ty: &syn::Type) -> Result<(syn::Type, Borrow)> {
+    let (owned, borrow) = match ty {
+        syn::Type::Reference(reference) => {
+            let borrow = if reference.mutability.is_some() { Borrow::Mut } else { Borrow::Shared };
+            let owned = match reference.elem.as_ref() {
+                syn::Type::Path(type_path) if type_path.qself.is_none() && type_path.path.is_ident("str") => syn::parse_quote!(String),
+                syn::Type::Slice(slice) => {
+                    let elem = &slice.elem;
+                    syn::parse_quote!(Vec<#elem>)
+                },
+                elem => elem.clone(),
+            };
+            (owned, borrow)
+        },
+        ty => (ty.clone(), Borrow::Owned),
+    };
+
+    let mut reference_finder = ReferenceFinder { has_reference: false };
+    reference_finder.visit_type(&owned);
+    if reference_finder.has_reference {
+        return Err(UntRustedError::UnsupportedFnArg(format!("`{}` borrows inside of another type, which cannot be deserialized", type_to_string(ty))));
+    }
+
+    return Ok((owned, borrow));
+}
+
+struct ReferenceFinder {
+    has_reference: bool,
+}
+
+impl<'ast> Visit<'ast> for ReferenceFinder {
+    fn visit_type_reference(&mut self, _reference: &'ast syn::TypeReference) {
+        self.has_reference = true;
+    }
+}
+
+/// Passes an owned param to the untrusted code the way it was borrowed
+pub(crate) fn borrow_arg(arg_name: &syn::Ident, borrow: Borrow) -> syn::Expr {
+    match borrow {
+        Borrow::Owned => syn::parse_quote!(#arg_name),
+        Borrow::Shared => syn::parse_quote!(&#arg_name),
+        Borrow::Mut => syn::parse_quote!(&mut #arg_name),
+    }
+}
+
+/// Prints a type the way it is usually written, e.g. `Vec<Option<String>>` instead of `Vec < Option < String > >`
+pub(crate) fn type_to_string(ty: &syn::Type) -> String {
+    let mut type_string = String::new();
+    push_tokens(ty.to_token_stream(), &mut type_string);
+    return type_string;
+}
+
+fn push_tokens(tokens: TokenStream, type_string: &mut String) {
+    let mut prev_is_word = false;
+    for token in tokens {
+        let is_word = matches!(token, TokenTree::Ident(_) | TokenTree::Literal(_));
+        match token {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    proc_macro2::Delimiter::Parenthesis => ("(", ")"),
+                    proc_macro2::Delimiter::Brace => ("{", "}"),
+                    proc_macro2::Delimiter::Bracket => ("[", "]"),
+                    proc_macro2::Delimiter::None => ("", ""),
+                };
+                type_string.push_str(open);
+                push_tokens(group.stream(), type_string);
+                type_string.push_str(close);
+            },
+            TokenTree::Punct(punct) => {
+                type_string.push(punct.as_char());
+                if punct.as_char() == ',' || punct.as_char() == ';' {
+                    type_string.push(' ');
+                }
+            },
+            token => {
+                if is_word && prev_is_word {
+                    type_string.push(' ');
+                }
+                type_string.push_str(&token.to_string());
+            },
+        }
+        prev_is_word = is_word;
+    }
+}
+
+/// Normalizes a type name so that a [`GuestTypeName`] can be compared with the type written in the untrusted code.
+/// Paths are dropped (`alloc::string::String` is `String`), and borrowed types are treated as their owned versions (`&str` is `String`)
+pub(crate) fn canonical_type_name(type_name: &str) -> String {
+    let mut canonical = String::new();
+    let mut word = String::new();
+
+    let mut chars = type_name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+
+        if c == ':' && chars.peek() == Some(&':') {
+            // drop the path prefix
+            chars.next();
+            word.clear();
+            continue;
+        }
+
+        push_canonical_word(&mut canonical, &mut word);
+
+        match c {
+            // lifetimes
+            '\'' => {
+                while chars.peek().is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+                    chars.next();
+                }
+            },
+            '&' => (),
+            '[' => canonical.push_str("Vec<"),
+            ']' => canonical.push('>'),
+            c if c.is_whitespace() => (),
+            c => canonical.push(c),
+        }
+    }
+    push_canonical_word(&mut canonical, &mut word);
+
+    return canonical;
+}
+
+fn push_canonical_word(canonical: &mut String, word: &mut String) {
+    match word.as_str() {
+        "" | "mut" => (),
+        "str" => canonical.push_str("String"),
+        word => canonical.push_str(word),
+    }
+    word.clear();
+}
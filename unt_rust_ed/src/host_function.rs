@@ -0,0 +1,140 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use extism::{CurrentPlugin, Function, UserData, Val, PTR};
+use proc_macro2::Span;
+use quote::quote;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::diagnostics::parse_str_as_generated;
+use crate::error::*;
+
+/// Name of the guest module holding the imports, the callable functions are generated next to it at the crate root
+const GUEST_IMPORTS_MODULE_NAME: &str = "__unt_rust_ed_host_functions";
+
+/// Name of the host function that the generated guest code reports errors, panics and the heap limit through.
+/// The untrusted code cannot name it (see `policy::check_host_access`), so unlike the extism error message, a report cannot be forged
+pub(crate) const GUEST_REPORT_FN_NAME: &str = "__unt_rust_ed_report";
+
+/// The report of the running call, passed to the plugin as its host context
+pub(crate) type GuestReportSlot = Arc<Mutex<Option<String>>>;
+
+pub type HostFunctionResult = std::result::Result<Vec<u8>, extism::Error>;
+
+/// Takes the serialized params, and returns the serialized return value
+pub type HostFunctionCall = fn(&[u8]) -> HostFunctionResult;
+
+/// A function on the host that untrusted code can call, implemented by `#[host_function]`
+pub trait HostFunction {
+    fn name() -> &'static str;
+    fn signature_as_string() -> &'static str;
+    fn call(input: &[u8]) -> HostFunctionResult;
+}
+
+/// Runs a host function for the code generated by `#[host_function]`. The params arrive as a json tuple, same as [`crate::Container::call_args`]
+#[doc(hidden)]
+pub fn call_host_function<A: DeserializeOwned, R: Serialize, F: FnOnce(A) -> R>(name: &str, input: &[u8], f: F) -> HostFunctionResult {
+    let args: A = serde_json::from_slice(input)?;
+
+    // a panic must not unwind through the wasm runtime
+    let output = panic::catch_unwind(AssertUnwindSafe(|| f(args)))
+        .map_err(|_| extism::Error::msg(format!("host function {} panicked", name)))?;
+
+    return Ok(serde_json::to_vec(&output)?);
+}
+
+pub(crate) fn create_extism_function(name: &str, call: HostFunctionCall) -> Function {
+    Function::new(name, [PTR], [PTR], UserData::new(()), move |plugin: &mut CurrentPlugin, inputs: &[Val], outputs: &mut [Val], _user_data| {
+        let input: &[u8] = plugin.memory_get_val(&inputs[0])?;
+        let output = call(input)?;
+        plugin.memory_set_val(&mut outputs[0], output)?;
+        Ok(())
+    })
+}
+
+/// Stores the report in the [`GuestReportSlot`] of the running call, replacing an earlier one
+pub(crate) fn create_report_function() -> Function {
+    Function::new(GUEST_REPORT_FN_NAME, [PTR], [], UserData::new(()), |plugin: &mut CurrentPlugin, inputs: &[Val], _outputs: &mut [Val], _user_data| {
+        let report = String::from_utf8_lossy(plugin.memory_get_val::<&[u8]>(&inputs[0])?).into_owned();
+        let slot = plugin.host_context::<GuestReportSlot>()?;
+        *slot.lock().unwrap_or_else(|err| err.into_inner()) = Some(report);
+        Ok(())
+    })
+}
+
+/// Creates the guest module that calls the report function, through a raw import so that it does not need the heap
+pub(crate) fn create_report_item() -> Result<syn::Item> {
+    let report_fn = syn::Ident::new(GUEST_REPORT_FN_NAME, Span::call_site());
+    return Ok(syn::parse2(quote! {
+        mod #report_fn {
+            #[link(wasm_import_module = "extism:host/user")]
+            extern "C" {
+                fn #report_fn(report: u64);
+            }
+
+            pub fn report(report: &[u8]) {
+                if let Ok(mem) = extism_pdk::Memory::from_bytes(report) {
+                    unsafe {
+                        #report_fn(mem.offset());
+                    }
+                }
+            }
+        }
+    })?);
+}
+
+/// Creates the guest side of the host functions: an import for each one, and a plain function with the original signature that calls it.
+/// `signatures` should be sorted, so that the generated code is the same every time
+pub(crate) fn create_guest_items(signatures: &[&str]) -> Result<Vec<syn::Item>> {
+    let imports_module = syn::Ident::new(GUEST_IMPORTS_MODULE_NAME, Span::call_site());
+
+    let mut imports = Vec::new();
+    let mut items = Vec::new();
+    for signature in signatures {
+        let sig: syn::Signature = parse_str_as_generated(signature)?;
+
+        let name = &sig.ident;
+        let mut arg_names = Vec::new();
+        let mut arg_types = Vec::new();
+        for input in &sig.inputs {
+            if let syn::FnArg::Typed(pat_type) = input {
+                arg_names.push(pat_type.pat.clone());
+                arg_types.push(pat_type.ty.clone());
+            }
+        }
+
+        let output_type = match &sig.output {
+            syn::ReturnType::Default => quote!(()),
+            syn::ReturnType::Type(_, ty) => quote!(#ty),
+        };
+
+        imports.push(quote! {
+            pub fn #name(input: extism_pdk::Json<(#(#arg_types,)*)>) -> extism_pdk::Json<#output_type>;
+        });
+
+        items.push(syn::parse2(quote! {
+            #[allow(dead_code)]
+            #sig {
+                let extism_pdk::Json(output) = unsafe { #imports_module::#name(extism_pdk::Json((#(#arg_names,)*))) }
+                    .expect(concat!("host function ", stringify!(#name), " failed"));
+                output
+            }
+        })?);
+    }
+
+    if !items.is_empty() {
+        items.push(syn::parse2(quote! {
+            mod #imports_module {
+                use super::*;
+
+                #[extism_pdk::host_fn]
+                extern "ExtismHost" {
+                    #(#imports)*
+                }
+            }
+        })?);
+    }
+
+    return Ok(items);
+}
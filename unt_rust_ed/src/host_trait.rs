@@ -0,0 +1,176 @@
+use proc_macro2::Span;
+use quote::quote;
+use syn::__private::ToTokens;
+
+use crate::{Container, PANIC_HOOK_MODULE_NAME};
+use crate::diagnostics::parse_str_as_generated;
+use crate::exports::{self, Borrow, Encoding, ExportedFunction};
+use crate::error::*;
+use crate::paths::PathResolver;
+use crate::policy::SourceSpan;
+
+/// A trait declared on the host that the untrusted code has to implement, implemented for `dyn Trait` by `#[exported_host_trait]`
+pub trait ExportedHostTrait {
+    /// Implements the trait by calling into a [`Container`]
+    type Proxy;
+
+    fn trait_name() -> &'static str;
+    fn traitdef_as_string() -> &'static str;
+    fn method_names() -> &'static [&'static str];
+    fn create_proxy(container: Container) -> Self::Proxy;
+}
+
+/// Finds the untrusted code's `impl Trait for X` at the crate root, checks that it has every method with the right receiver, params and return type,
+/// and then creates an export for every method. Each export calls a single `X::default()` instance.
+///
+/// The types are compared after resolving their paths and aliases, and ignoring references and lifetimes, so a mismatch that this lets through
+/// (e.g. `&str` for `String`) is still caught by rustc
+pub(crate) fn create_guest_items(traitdef: &str, items: &[syn::Item], path_resolver: &PathResolver, exports: &mut Vec<ExportedFunction>) -> Result<Vec<syn::Item>> {
+    let item_trait: syn::ItemTrait = parse_str_as_generated(traitdef)?;
+    let trait_name = item_trait.ident.to_string();
+
+    let impls: Vec<&syn::ItemImpl> = items.iter()
+        .filter_map(|item| match item {
+            syn::Item::Impl(item_impl) => Some(item_impl),
+            _ => None,
+        })
+        .filter(|item_impl| match &item_impl.trait_ {
+            Some((None, path, _)) => path.segments.last().is_some_and(|segment| segment.ident == trait_name),
+            _ => false,
+        })
+        .collect();
+
+    let item_impl = match impls.as_slice() {
+        [item_impl] => *item_impl,
+        [] => return Err(UntRustedError::InvalidTraitImpl {
+            trait_name: trait_name.clone(),
+            problems: vec![format!("missing `impl {} for ..` at the crate root", trait_name)],
+        }),
+        _ => return Err(UntRustedError::InvalidTraitImpl {
+            trait_name: trait_name.clone(),
+            problems: vec![format!("{} is implemented more than once", trait_name)],
+        }),
+    };
+
+    let impl_fns: Vec<&syn::ImplItemFn> = item_impl.items.iter()
+        .filter_map(|impl_item| match impl_item {
+            syn::ImplItem::Fn(impl_fn) => Some(impl_fn),
+            _ => None,
+        })
+        .collect();
+
+    let mut problems = Vec::new();
+    let mut trait_fns = Vec::new();
+    for trait_item in &item_trait.items {
+        let syn::TraitItem::Fn(trait_fn) = trait_item else {
+            continue;
+        };
+        trait_fns.push(trait_fn);
+
+        let Some(impl_fn) = impl_fns.iter().find(|impl_fn| impl_fn.sig.ident == trait_fn.sig.ident) else {
+            if trait_fn.default.is_none() {
+                problems.push(format!("missing method `{}`", trait_fn.sig.ident));
+            }
+            continue;
+        };
+
+        let span = SourceSpan::from(impl_fn.sig.ident.span());
+        if impl_fn.sig.inputs.len() != trait_fn.sig.inputs.len() {
+            problems.push(format!("`{}` at {} takes {} params, but the trait has {}",
+                trait_fn.sig.ident, span, impl_fn.sig.inputs.len(), trait_fn.sig.inputs.len()));
+        } else if !matches!(impl_fn.sig.receiver(), Some(receiver) if receiver.reference.is_some() && receiver.mutability.is_none()) {
+            problems.push(format!("`{}` at {} has to take `&self`", trait_fn.sig.ident, span));
+        } else {
+            let param_types = |sig: &syn::Signature| -> Vec<syn::Type> {
+                sig.inputs.iter()
+                    .filter_map(|input| match input {
+                        syn::FnArg::Typed(pat_type) => Some((*pat_type.ty).clone()),
+                        syn::FnArg::Receiver(_) => None,
+                    })
+                    .collect()
+            };
+
+            for (idx, (impl_ty, trait_ty)) in param_types(&impl_fn.sig).iter().zip(param_types(&trait_fn.sig).iter()).enumerate() {
+                if !is_same_type(path_resolver, impl_ty, trait_ty) {
+                    problems.push(format!("`{}` at {} takes `{}` as param {}, but the trait has `{}`",
+                        trait_fn.sig.ident, span, exports::type_to_string(impl_ty), idx + 1, exports::type_to_string(trait_ty)));
+                }
+            }
+
+            let (impl_output, trait_output) = (return_type(&impl_fn.sig), return_type(&trait_fn.sig));
+            if !is_same_type(path_resolver, &impl_output, &trait_output) {
+                problems.push(format!("`{}` at {} returns `{}`, but the trait returns `{}`",
+                    trait_fn.sig.ident, span, exports::type_to_string(&impl_output), exports::type_to_string(&trait_output)));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(UntRustedError::InvalidTraitImpl {
+            trait_name,
+            problems,
+        });
+    }
+
+    let trait_ident = &item_trait.ident;
+    let self_ty = &item_impl.self_ty;
+    let instance = syn::Ident::new(&format!("__UNT_RUST_ED_{}", trait_name.to_uppercase()), Span::call_site());
+
+    let mut guest_items: Vec<syn::Item> = vec![syn::parse2(quote! {
+        thread_local! {
+            static #instance: #self_ty = <#self_ty as ::core::default::Default>::default();
+        }
+    })?];
+
+    let panic_hook_module = syn::Ident::new(PANIC_HOOK_MODULE_NAME, Span::call_site());
+    for trait_fn in trait_fns {
+        let method_name = &trait_fn.sig.ident;
+        let export_name = syn::Ident::new(&format!("{}__{}", trait_name, method_name), Span::call_site());
+
+        // borrowed params are deserialized as owned values, and then borrowed again for the call
+        let mut arg_names = Vec::new();
+        let mut arg_types = Vec::new();
+        let mut call_args = Vec::new();
+        for (idx, input) in trait_fn.sig.inputs.iter().skip(1).enumerate() {
+            if let syn::FnArg::Typed(pat_type) = input {
+                let arg_name = syn::Ident::new(&format!("arg{}", idx), Span::call_site());
+                let (owned_type, borrow) = exports::owned_type(&pat_type.ty)?;
+                call_args.push(exports::borrow_arg(&arg_name, borrow));
+                arg_names.push(if borrow == Borrow::Mut { quote!(mut #arg_name) } else { quote!(#arg_name) });
+                arg_types.push(owned_type.to_token_stream());
+            }
+        }
+
+        let output_type = match &trait_fn.sig.output {
+            syn::ReturnType::Default => quote!(()),
+            syn::ReturnType::Type(_, ty) => quote!(#ty),
+        };
+
+        exports.push(ExportedFunction::new(format!("{}::{}", trait_name, method_name), export_name.to_string(), &trait_fn.attrs,
+                                           &trait_fn.sig.inputs, &trait_fn.sig.output, Encoding::JsonTuple, Encoding::Json));
+
+        guest_items.push(syn::parse2(quote! {
+            #[allow(non_snake_case)]
+            #[extism_pdk::plugin_fn]
+            pub fn #export_name(extism_pdk::Json((#(#arg_names,)*)): extism_pdk::Json<(#(#arg_types,)*)>) -> extism_pdk::FnResult<extism_pdk::Json<#output_type>> {
+                crate::#panic_hook_module::install();
+                Ok(extism_pdk::Json(#instance.with(|instance| #trait_ident::#method_name(instance, #(#call_args),*))))
+            }
+        })?);
+    }
+
+    return Ok(guest_items);
+}
+
+fn return_type(sig: &syn::Signature) -> syn::Type {
+    match &sig.output {
+        syn::ReturnType::Default => syn::parse_quote!(()),
+        syn::ReturnType::Type(_, ty) => (**ty).clone(),
+    }
+}
+
+/// Both the trait and the impl are at the crate root
+fn is_same_type(path_resolver: &PathResolver, a: &syn::Type, b: &syn::Type) -> bool {
+    let canonical = |ty: &syn::Type| exports::canonical_type_name(&exports::type_to_string(&path_resolver.resolve_type(&[], ty)));
+    return canonical(a) == canonical(b);
+}
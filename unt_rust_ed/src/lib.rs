@@ -1,42 +1,111 @@
-pub mod error;
+#![allow(clippy::needless_return)]
 
-use std::cmp::Ordering;
+pub mod error;
+pub mod cache;
+pub mod sandbox;
+pub mod policy;
+pub mod diagnostics;
+pub mod host_function;
+pub mod host_trait;
+pub mod exports;
+pub mod pool;
+mod paths;
+mod watchdog;
+mod memory;
+
+// lets `#[exported_host_type]`, `#[exported_host_trait]` and `#[host_function]` refer to `unt_rust_ed::` from within this crate too
+extern crate self as unt_rust_ed;
+
+use std::env;
 use std::fs::{self, File};
-use std::io::{Write, Read};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
 use std::ops::Deref;
 use std::collections::{HashSet, HashMap};
+use std::sync::Arc;
 
 use log::{debug, warn};
 
-use extism::{Manifest, Plugin, Wasm, ToBytes, FromBytes};
+use extism::{CompiledPlugin, Manifest, Plugin, PluginBuilder, Wasm, ToBytes, FromBytes};
 pub use extism_manifest::MemoryOptions;
 pub use extism_convert::Json;
 
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use tempfile::TempDir;
 
 use syn::Token;
 use syn::token::{Paren, Bracket};
 use syn::punctuated::Punctuated;
 use syn::__private::Span;
-use syn::__private::ToTokens;
 
 use crate::error::*;
+use crate::cache::{CACHE_FILE_EXTENSION, NATIVE_CODE_DIR_EXTENSION, NATIVE_CODE_DIR_NAME, CompileCacheKey, lock_dir, native_code_cache_config, read_compiled_project, toolchain_versions, write_compiled_project};
+pub use crate::cache::{CompileCache, CompileCacheStats};
+pub use crate::sandbox::BuildSandbox;
+pub use crate::policy::{SourcePolicy, PolicySet};
+pub use crate::diagnostics::{Diagnostic, DiagnosticLevel, DiagnosticSpan};
+use crate::diagnostics::{SourceMap, parse_cargo_messages};
+use crate::paths::PathResolver;
+use crate::watchdog::{Interruption, Watchdog};
+use crate::memory::{HEAP_LIMIT_SLACK_BYTES, MemoryLimits};
+pub use crate::watchdog::CancelHandle;
+pub use crate::pool::{ContainerPool, PoolMetrics, PoolOptions, PooledContainer, ResetMode};
+pub use crate::host_function::{HostFunction, HostFunctionCall, HostFunctionResult, call_host_function};
+use crate::host_function::{GUEST_REPORT_FN_NAME, GuestReportSlot};
+pub use crate::host_trait::ExportedHostTrait;
+pub use crate::exports::{EXPORTS_SECTION_NAME, Encoding, ExportedFunction, GuestTypeName, TypedFunc};
+use crate::exports::DeclaredExport;
+use crate::sandbox::run_cargo;
 
 type ProjectHash = String;
 
+/// With the `span-locations` feature, `proc_macro2` keeps the text of everything parsed on a thread for as long as the thread lives,
+/// which would leak the untrusted code of every compile. This frees it, which also invalidates any other span created on the thread
+struct InvalidateSpansOnDrop;
+
+impl Drop for InvalidateSpansOnDrop {
+    fn drop(&mut self) {
+        proc_macro2::extra::invalidate_current_thread_spans();
+    }
+}
+
 pub trait ExportedHostType {
     fn typename() -> &'static str;
     fn typedef_as_string() -> &'static str;
 }
 
-/// Returns the number of bytes in a page of memory.
+/// WebAssembly memory is always allocated in 64 KiB pages, regardless of the host OS page size
+pub const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+/// Prefix of the report from the guest heap limiter (see [`UntrustedRustProject::with_max_heap_bytes`])
+const HEAP_LIMIT_EXCEEDED_MSG: &str = "unt-rust-ed heap limit exceeded:";
+
+/// Prefix of the report sent when an exported function returns `Err`, followed by the error as json
+const GUEST_ERROR_MSG: &str = "unt-rust-ed guest error:";
+
+/// Prefix of the report sent by the guest panic hook, followed by `[message, line, column]` as json
+const GUEST_PANIC_MSG: &str = "unt-rust-ed guest panic:";
+
+/// Fuel limits whose compiled untrusted code a container keeps (see [`Container::call_with`])
+const MAX_COMPILED_PLUGINS_PER_CONTAINER: usize = 8;
+
+/// Guest module with the panic hook, which every exported function installs before running the untrusted code
+pub(crate) const PANIC_HOOK_MODULE_NAME: &str = "__unt_rust_ed_panic_hook";
+
+/// Types without generics that serde can serialize, matched by the last path segment
+const SERIALIZABLE_PRIMITIVES: &[&str] = &["bool", "char", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+                                           "f32", "f64", "String"];
+
+/// Generic types that serde can serialize when all of their type params can be, matched by the last path segment
+const SERIALIZABLE_CONTAINERS: &[&str] = &["Vec", "VecDeque", "Option", "Box", "HashMap", "BTreeMap", "HashSet", "BTreeSet"];
+
+/// Returns the number of bytes in a page of WebAssembly memory.
 /// This is useful for determining how many pages to give to an untrusted project
 pub fn get_page_size() -> usize {
-    return page_size::get();
+    return WASM_PAGE_SIZE;
 }
 
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -55,18 +124,50 @@ impl WasmCompileTarget {
     }
 }
 
+/// Decides which functions in the `rust_code` get exported (and so are callable through a [`Container`])
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub enum ExportPolicy {
+    /// Export every `pub fn`
+    #[default]
+    AllPublic,
+    /// Only export functions tagged with `#[export]`
+    Tagged,
+    /// Only export the listed functions. Names may have module prefixes (e.g. `foo::exported_fn`)
+    Allowlist(HashSet<String>),
+}
+
+/// A `#[host_function]` registered with [`UntrustedRustProject::with_host_function`]
+#[derive(Debug, Clone)]
+struct RegisteredHostFunction {
+    signature: String,
+    call: HostFunctionCall,
+}
+
 #[derive(Debug, Clone)]
 pub struct UntrustedRustProject {
     cache_path: Option<PathBuf>,
+    compile_cache: Option<CompileCache>,
+    cache_native_code: bool,
     rust_code: String,
     runtime_memory_options: MemoryOptions,
     runtime_timeout_ms: Option<u64>,
+    fuel_limit: Option<u64>,
+    max_heap_bytes: Option<usize>,
     target: WasmCompileTarget,
     /// map type name to typedef
     exported_host_types: HashMap<String, String>,
-    /// type names to replace during compilation. May contain module separators ('::')
+    exported_host_traits: HashMap<String, String>,
+    host_functions: HashMap<String, RegisteredHostFunction>,
+    /// normalized paths of the types to replace during compilation, e.g. `my_sdk::Order`
     sdk_types: HashSet<String>,
     dependencies: HashSet<String>,
+    export_policy: ExportPolicy,
+    /// map fn path to the entry point that the host expects
+    declared_exports: HashMap<String, DeclaredExport>,
+    build_sandbox: Option<BuildSandbox>,
+    compile_timeout: Option<Duration>,
+    check_dir: Option<PathBuf>,
+    policies: Vec<Arc<dyn SourcePolicy>>,
 }
 
 impl UntrustedRustProject {
@@ -74,26 +175,80 @@ impl UntrustedRustProject {
     pub fn new(rust_code: &str) -> Self {
         Self {
             cache_path: None,
+            compile_cache: None,
+            cache_native_code: false,
             rust_code: rust_code.into(),
             runtime_memory_options: MemoryOptions::default(),
             runtime_timeout_ms: None,
+            fuel_limit: None,
+            max_heap_bytes: None,
             target: WasmCompileTarget::default(),
             exported_host_types: HashMap::new(),
+            exported_host_traits: HashMap::new(),
+            host_functions: HashMap::new(),
             sdk_types: HashSet::new(),   
             dependencies: HashSet::new(),       
+            export_policy: ExportPolicy::default(),
+            declared_exports: HashMap::new(),
+            build_sandbox: None,
+            compile_timeout: None,
+            check_dir: None,
+            policies: Vec::new(),
         }
     }
 
-    fn calculate_hash(&self) -> ProjectHash {
-        let mut exported_host_types: Vec<String> = self.exported_host_types.iter().map(|(s1, s2)| format!("({}+{})", s1, s2)).collect();
+    fn calculate_cache_key(&self) -> Result<CompileCacheKey> {
+        let mut exported_host_types: Vec<(String, String)> = self.exported_host_types.iter().map(|(s1, s2)| (s1.clone(), s2.clone())).collect();
         exported_host_types.sort();
+
+        let mut exported_host_traits: Vec<(String, String)> = self.exported_host_traits.iter().map(|(s1, s2)| (s1.clone(), s2.clone())).collect();
+        exported_host_traits.sort();
         
         let mut sdk_types: Vec<String> = self.sdk_types.iter().map(String::clone).collect();
         sdk_types.sort();
-        
-        let hashable = format!("{}+{:?}+{:?}+{:?}", self.rust_code, self.target, exported_host_types, sdk_types);
 
-        return sha256::digest(hashable);
+        let export_policy = match &self.export_policy {
+            ExportPolicy::Allowlist(fn_paths) => {
+                let mut fn_paths: Vec<String> = fn_paths.iter().map(String::clone).collect();
+                fn_paths.sort();
+                format!("Allowlist({:?})", fn_paths)
+            },
+            export_policy => format!("{:?}", export_policy),
+        };
+
+        let (rustc_version, cargo_version) = toolchain_versions()?;
+
+        return Ok(CompileCacheKey {
+            rust_code_hash: sha256::digest(&self.rust_code),
+            target: self.target,
+            exported_host_types,
+            exported_host_traits,
+            host_functions: self.sorted_host_function_signatures().into_iter().map(String::from).collect(),
+            sdk_types,
+            dependencies: self.sorted_dependencies(),
+            export_policy,
+            declared_exports: self.sorted_declared_exports().iter()
+                .map(|(name, declared_export)| format!("{}: {} (required={})", name, declared_export.signature, declared_export.required))
+                .collect(),
+            max_heap_bytes: self.max_heap_bytes,
+            policies: self.policies.iter().map(|source_policy| format!("{:?}", source_policy)).collect(),
+            cargo_toml_hash: sha256::digest(self.cargo_toml_content()),
+            rustc_version,
+            cargo_version,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        });
+    }
+
+    fn sorted_declared_exports(&self) -> Vec<(&String, &DeclaredExport)> {
+        let mut declared_exports: Vec<(&String, &DeclaredExport)> = self.declared_exports.iter().collect();
+        declared_exports.sort_by_key(|(name, _)| *name);
+        return declared_exports;
+    }
+
+    fn sorted_dependencies(&self) -> Vec<String> {
+        let mut dependencies: Vec<String> = self.dependencies.iter().map(String::clone).collect();
+        dependencies.sort();
+        return dependencies;
     }
 
     pub fn with_caching<P: AsRef<Path>>(mut self, cache_path: P) -> Self {
@@ -101,33 +256,69 @@ impl UntrustedRustProject {
         self
     }
 
+    /// Caps the total wasm memory of the container. This is rounded up to a whole number of wasm pages (see [`WASM_PAGE_SIZE`]),
+    /// and it includes the stack and static data of the untrusted code (roughly 1 MB for rust), not just the heap.
+    /// The host allocates the wasm memory and refuses to grow it past the limit, which fails the call with [`UntRustedError::RuntimeExceededMemory`]
     pub fn with_max_memory_bytes(mut self, num_bytes: usize) -> Self {
-        let page_size = get_page_size();
-        let num_pages = if num_bytes % page_size == 0 {
-            num_bytes / page_size
-        } else {
-            num_bytes / page_size + 1
-        };
+        let num_pages = num_bytes.div_ceil(WASM_PAGE_SIZE);
         self.runtime_memory_options = MemoryOptions {
             max_pages: Some(num_pages as u32),
+            ..MemoryOptions::default()
         };
         self
     }
 
+    /// Caps how many bytes the untrusted code may have allocated on its heap at once.
+    /// The host refuses to grow the wasm memory by more than this plus 1 MiB (for the allocator's bookkeeping, and the inputs and outputs),
+    /// so going around the allocator (e.g. with `memory_grow`) does not get around the limit. Going over that fails the call with [`UntRustedError::RuntimeExceededMemory`].
+    /// A global allocator that is compiled into the untrusted code also counts the allocated bytes, so that going over the exact limit through it
+    /// fails the call with [`UntRustedError::RuntimeExceededHeapLimit`] instead
+    pub fn with_max_heap_bytes(mut self, num_bytes: usize) -> Self {
+        self.max_heap_bytes = Some(num_bytes);
+        self
+    }
+
     pub fn with_target(mut self, target: WasmCompileTarget) -> Self {
         self.target = target;
         self
     }
 
+    /// Lets the untrusted code call a function marked with `#[host_function]`, as if it was a normal function with the same signature
+    pub fn with_host_function<F: HostFunction>(mut self) -> Self {
+        self.host_functions.insert(F::name().to_string(), RegisteredHostFunction {
+            signature: F::signature_as_string().to_string(),
+            call: F::call,
+        });
+        self
+    }
+
+    fn sorted_host_function_signatures(&self) -> Vec<&str> {
+        let mut signatures: Vec<&str> = self.host_functions.values().map(|host_function| host_function.signature.as_str()).collect();
+        signatures.sort();
+        return signatures;
+    }
+
+    fn host_function_calls(&self) -> Vec<(String, HostFunctionCall)> {
+        self.host_functions.iter().map(|(name, host_function)| (name.clone(), host_function.call)).collect()
+    }
+
+    /// Requires the untrusted code to implement a trait marked with `#[exported_host_trait]` (registered as `dyn Trait`), with exactly one
+    /// `impl Trait for X` at the crate root where `X: Default`. Its methods can then be called through [`Container::into_proxy`]
+    pub fn with_exported_host_trait<T: ExportedHostTrait + ?Sized>(mut self) -> Self {
+        self.exported_host_traits.insert(T::trait_name().to_string(), T::traitdef_as_string().to_string());
+        self
+    }
+
     /// These are "plain-old-data" types, and they exist mainly as a convenience. For more flexibility, use an sdk crate and tag the types as sdk types
     pub fn with_exported_host_type<T: ExportedHostType>(mut self) -> Self {
         self.exported_host_types.insert(T::typename().to_string(), T::typedef_as_string().to_string());
         self
     }
 
-    /// These types are imported by the `rust_code`, and we need to know to 'jsonify' them
+    /// These types are imported by the `rust_code`, and we need to know to 'jsonify' them. `typename` is the full path (e.g. `my_sdk::Order`),
+    /// and the untrusted code can refer to it through `use` items, renames and aliases. A bare name (e.g. `Order`) matches any type with that name
     pub fn with_sdk_type(mut self, typename: &str) -> Self {
-        self.sdk_types.insert(typename.to_string());
+        self.sdk_types.insert(paths::normalize_path(typename));
         self
     }
 
@@ -136,6 +327,13 @@ impl UntrustedRustProject {
         self
     }
 
+    /// Caps how much fuel (roughly, wasm instructions) each call may use. Unlike [`Self::with_runtime_timeout_ms`], this does not depend on
+    /// how loaded the machine is, so the same call always passes or fails. Going over the limit fails the call with [`UntRustedError::FuelExhausted`]
+    pub fn with_fuel_limit(mut self, fuel: u64) -> Self {
+        self.fuel_limit = Some(fuel);
+        self
+    }
+
     pub fn with_runtime_memory_options(mut self, mem_opts: MemoryOptions) -> Self {
         self.runtime_memory_options = mem_opts;
         self
@@ -146,46 +344,121 @@ impl UntrustedRustProject {
         self
     }
 
-    fn load_cached_compiled<P: AsRef<Path>>(cache_path: P, project_hash: &ProjectHash) -> Result<CompiledUntrustedRustProject> {
-        let fname = format!("{}.unt-rust-ed-c", cache_path.as_ref().to_str().unwrap());
+    pub fn with_export_policy(mut self, export_policy: ExportPolicy) -> Self {
+        self.export_policy = export_policy;
+        self
+    }
+
+    /// Fails the compile with [`UntRustedError::InvalidExports`] unless the untrusted code exports `fn_name` with `signature`,
+    /// which is written as a fn type, e.g. `fn(Inputs) -> Outputs`. `fn_name` may have module prefixes (e.g. `foo::exported_fn`).
+    /// This is checked before building, instead of at the first [`Container::call`]
+    pub fn with_required_export(mut self, fn_name: &str, signature: &str) -> Self {
+        self.declared_exports.insert(fn_name.to_string(), DeclaredExport {
+            signature: signature.to_string(),
+            required: true,
+        });
+        self
+    }
+
+    /// Same as [`Self::with_required_export`], except that the untrusted code may leave out `fn_name`
+    pub fn with_optional_export(mut self, fn_name: &str, signature: &str) -> Self {
+        self.declared_exports.insert(fn_name.to_string(), DeclaredExport {
+            signature: signature.to_string(),
+            required: false,
+        });
+        self
+    }
+
+    /// Checks the untrusted code against `policy` before building it. This can be called more than once, and every policy has to pass.
+    /// Violations fail the compile with [`UntRustedError::PolicyViolation`]
+    pub fn with_policy<P: SourcePolicy + 'static>(mut self, policy: P) -> Self {
+        self.policies.push(Arc::new(policy));
+        self
+    }
+
+    /// Runs `cargo build` with a cleared environment, a private `CARGO_HOME` and resource limits (see [`BuildSandbox`])
+    pub fn with_build_sandbox(mut self, build_sandbox: BuildSandbox) -> Self {
+        self.build_sandbox = Some(build_sandbox);
+        self
+    }
+
+    /// Kills `cargo build` if it has not finished after `timeout`, failing the compile with [`UntRustedError::CompileTimeout`]
+    pub fn with_compile_timeout(mut self, timeout: Duration) -> Self {
+        self.compile_timeout = Some(timeout);
+        self
+    }
 
-        let mut file = File::open(&fname)
-            .map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+    /// Directory for the persistent workspaces used by [`Self::check`]. Without it, every check gets a fresh temp directory, and so has to check the dependencies again.
+    /// The directory should only be writable by the current user, since anyone who can change the workspaces can change what gets checked
+    pub fn with_check_dir<P: AsRef<Path>>(mut self, check_dir: P) -> Self {
+        self.check_dir = Some(check_dir.as_ref().to_path_buf());
+        self
+    }
 
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+    /// Stores compiled projects in a [`CompileCache`] directory, instead of the single file used by [`Self::with_caching`]
+    pub fn with_compile_cache(mut self, compile_cache: CompileCache) -> Self {
+        self.compile_cache = Some(compile_cache);
+        self
+    }
 
-        let reader = flexbuffers::Reader::get_root(buf.as_slice()).map_err(|err| UntRustedError::SerdeReader(fname.clone(), err))?;
+    /// Also caches the native code that the wasm is compiled into when creating a container, next to the compiled project
+    /// (see [`Self::with_caching`] and [`Self::with_compile_cache`]), so that later containers do not have to compile it again.
+    /// Does nothing without one of those caches.
+    ///
+    /// Unlike the wasm, the native code is not sandboxed: the wasm runtime loads it as it is. Anyone who can write to the cache directory
+    /// can therefore run their own code on the host. The native code directory is created so that only the current user can access it,
+    /// and a directory that is not like that is not used (the wasm is compiled again instead). The cache directory itself should be just as private
+    pub fn with_native_code_caching(mut self) -> Self {
+        self.cache_native_code = true;
+        self
+    }
 
-        let cached_compiled_project = CompiledUntrustedRustProject::deserialize(reader).map_err(|err| UntRustedError::SerdeDeserialize(fname.clone(), err))?;
+    fn native_code_dir(&self, project_hash: &str) -> Option<PathBuf> {
+        if !self.cache_native_code {
+            return None;
+        }
 
-        // check that the hash matches
-        if cached_compiled_project.project_hash.cmp(project_hash) != Ordering::Equal {
-            return Err(UntRustedError::CachedHashMismatch);
+        // one directory per entry, so that evicting the entry can remove its native code
+        if let Some(compile_cache) = &self.compile_cache {
+            return Some(compile_cache.dir().join(NATIVE_CODE_DIR_NAME).join(project_hash));
         }
 
-        return Ok(cached_compiled_project);
+        return self.cache_path.as_ref().map(|cache_path| PathBuf::from(format!("{}.{}", cache_path.display(), NATIVE_CODE_DIR_EXTENSION)));
     }
 
-    fn save_cached_compiled<P: AsRef<Path>>(cache_path: P, compiled_project: &CompiledUntrustedRustProject) -> Result<()> {
-        let fname = format!("{}.unt-rust-ed-c", cache_path.as_ref().to_str().unwrap());
+    fn load_cached_compiled<P: AsRef<Path>>(cache_path: P, cache_key: &CompileCacheKey) -> Result<CompiledUntrustedRustProject> {
+        let fname = format!("{}.{}", cache_path.as_ref().to_str().unwrap(), CACHE_FILE_EXTENSION);
+
+        let cached_compiled_project = read_compiled_project(Path::new(&fname))?;
 
-        let mut file = File::create(&fname).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+        // check that the key matches
+        if cached_compiled_project.cache_key != *cache_key {
+            return Err(UntRustedError::CachedHashMismatch(cached_compiled_project.cache_key.diff(cache_key)));
+        }
 
-        let mut s = flexbuffers::FlexbufferSerializer::new();
-        compiled_project.serialize(&mut s).map_err(|err| UntRustedError::SerdeSerialize(fname.clone(), err))?;
+        return Ok(cached_compiled_project);
+    }
 
-        file.write_all(s.view()).map_err(|err| UntRustedError::IoError { resource: fname.clone(), err })?;
+    fn save_cached_compiled<P: AsRef<Path>>(cache_path: P, compiled_project: &CompiledUntrustedRustProject) -> Result<()> {
+        let fname = format!("{}.{}", cache_path.as_ref().to_str().unwrap(), CACHE_FILE_EXTENSION);
 
-        return Ok(());
+        return write_compiled_project(Path::new(&fname), compiled_project);
     }
 
-    /// Converts the modules into compiled modules containing WASM
+    /// Converts the modules into compiled modules containing WASM.
+    /// This (and [`Self::check`]) invalidates every `proc_macro2` span, since it frees the parsed code
     pub fn compile(&self) -> Result<CompiledUntrustedRustProject> {
-        let project_hash: ProjectHash = self.calculate_hash();
+        let cache_key: CompileCacheKey = self.calculate_cache_key()?;
+        let project_hash: ProjectHash = cache_key.hash();
+
+        let cached_compiled_project = if let Some(compile_cache) = &self.compile_cache {
+            Some(compile_cache.load(&cache_key))
+        } else {
+            self.cache_path.as_ref().map(|cache_path| Self::load_cached_compiled(cache_path, &cache_key))
+        };
 
-        if let Some(cache_path) = &self.cache_path {
-            match Self::load_cached_compiled(cache_path, &project_hash) {
+        if let Some(cached_compiled_project) = cached_compiled_project {
+            match cached_compiled_project {
                 Ok(mut cached_compiled_project) => {
 
                     // make sure the manifest is using the correct/updated options
@@ -196,6 +469,11 @@ impl UntrustedRustProject {
                     if let Some(runtime_timeout_ms) = self.runtime_timeout_ms {
                         cached_compiled_project.manifest = cached_compiled_project.manifest.with_timeout(Duration::from_millis(runtime_timeout_ms));
                     }
+                    cached_compiled_project.fuel_limit = self.fuel_limit;
+
+                    // host functions are not serialized
+                    cached_compiled_project.host_functions = self.host_function_calls();
+                    cached_compiled_project.native_code_dir = self.native_code_dir(&project_hash);
 
                     return Ok(cached_compiled_project);
                 },
@@ -226,16 +504,17 @@ impl UntrustedRustProject {
         // create modules in src/lib.rs file in temp directory.
         // For every exported function, create a copy with the module underscore prefix, and tag it as wasm-exported
         // Also perform checks such as ensuring that other functions do not start with any of the module names and an underscore
-        self.write_rust_code_to_cargo_dir(&cargo_src_path)?;
+        let source_map = self.write_rust_code_to_cargo_dir(&cargo_src_path)?;
 
         // compile project to wasm by spawning cargo as a subprocess
-        let built_wasm_file_path: PathBuf = self.cargo_build_to_wasm(&tmp_cargo_dir)?;
+        let (built_wasm_file_path, diagnostics) = self.cargo_build_to_wasm(&tmp_cargo_dir, &source_map)?;
 
         let built_wasm_bytes: Vec<u8> = fs::read(&built_wasm_file_path).map_err(|err| UntRustedError::IoError {
    resource: format!("{:?}", built_wasm_file_path),
    err,
    })?;
 
+        let exports = exports::read_metadata(&built_wasm_bytes)?;
         let wasm = Wasm::data(built_wasm_bytes);
 
         let manifest = Manifest::new(vec![wasm])
@@ -248,31 +527,94 @@ impl UntrustedRustProject {
             manifest
         };
 
+        let native_code_dir = self.native_code_dir(&project_hash);
         let compiled_project = CompiledUntrustedRustProject {
             project_hash,
+            cache_key,
             manifest,
+            fuel_limit: self.fuel_limit,
             target: self.target,
+            diagnostics,
+            source_map,
+            exports,
+            host_functions: self.host_function_calls(),
+            native_code_dir,
         };
 
-        if let Some(cache_path) = &self.cache_path {
+        let saved = if let Some(compile_cache) = &self.compile_cache {
+            Some(compile_cache.save(&compiled_project))
+        } else {
             // save to file
-            match Self::save_cached_compiled(cache_path, &compiled_project) {
-                Ok(_) => (),
-                Err(err) => {
-                    warn!("failed to cache compiled project to file: {}", err);
-                }
-            }
+            self.cache_path.as_ref().map(|cache_path| Self::save_cached_compiled(cache_path, &compiled_project))
+        };
+
+        if let Some(Err(err)) = saved {
+            warn!("failed to cache compiled project to file: {}", err);
         }
 
         return Ok(compiled_project);
     }
 
+    /// Runs the same code generation as [`Self::compile`], but only `cargo check`s the result, which is much faster for editor feedback.
+    /// With [`Self::with_check_dir`], the workspace is kept between calls, so dependencies are only checked once and later checks only re-check the untrusted code.
+    ///
+    /// Returns the warnings, or [`UntRustedError::CompileError`] with every diagnostic if there are errors
+    pub fn check(&self) -> Result<Vec<Diagnostic>> {
+        // a fixed directory under the system temp directory could be set up by another user beforehand (e.g. with symlinks),
+        // so only a directory that the host picked is reused
+        let tmp_check_dir;
+        let check_dir = match &self.check_dir {
+            Some(check_dir) => check_dir.clone(),
+            None => {
+                tmp_check_dir = TempDir::new().map_err(|err| UntRustedError::IoError {
+                    resource: "TempDir".into(),
+                    err,
+                })?;
+                tmp_check_dir.path().to_path_buf()
+            },
+        };
+
+        // one workspace per Cargo.toml, so that projects with different dependencies do not keep invalidating each other's builds
+        let cargo_toml_content = self.cargo_toml_content();
+        let workspace_dir = check_dir.join(&sha256::digest(&cargo_toml_content)[..16]);
+        let cargo_src_path = workspace_dir.join("src");
+
+        fs::create_dir_all(&cargo_src_path).map_err(|err| UntRustedError::IoError {
+   resource: format!("{:?}", cargo_src_path),
+   err,
+   })?;
+
+        // concurrent checks would overwrite each other's lib.rs
+        let _lock = lock_dir(&workspace_dir, true)?;
+
+        // only write Cargo.toml the first time, rewriting it would make cargo resolve the dependencies again
+        let cargo_toml_path = workspace_dir.join("Cargo.toml");
+        if fs::read_to_string(&cargo_toml_path).ok().as_deref() != Some(cargo_toml_content.as_str()) {
+            self.write_cargo_toml(&cargo_toml_path)?;
+        }
+
+        let source_map = self.write_rust_code_to_cargo_dir(&cargo_src_path)?;
+
+        debug!("start cargo check (dir={:?})", workspace_dir);
+
+        return self.run_cargo_for_target(&["check"], &workspace_dir, &source_map);
+    }
+
     fn write_cargo_toml<P: AsRef<Path>>(&self, cargo_toml_path: P) -> Result<()> {
         let mut cargo_toml_file = File::create(&cargo_toml_path).map_err(|err| UntRustedError::IoError {
    resource: format!("{:?}", cargo_toml_path.as_ref()),
    err,
    })?;
 
+        cargo_toml_file.write_all(self.cargo_toml_content().as_bytes()).map_err(|err| UntRustedError::IoError {
+   resource: format!("{:?}", cargo_toml_path.as_ref()),
+   err,
+   })?;
+
+        return Ok(());
+    }
+
+    fn cargo_toml_content(&self) -> String {
         let mut content: String = "[package]
     name = \"test-wasm\"
     version = \"0.1.0\"
@@ -285,69 +627,106 @@ impl UntrustedRustProject {
     extism-pdk = \"1.0.0-rc1\"
     serde = { version = \"1.0\", features = [\"derive\"] }".into();
 
-        for dep in &self.dependencies {
+        // sorted, so that the same dependencies always produce the same Cargo.toml
+        for dep in self.sorted_dependencies() {
             content.push('\n');
-            content.push_str(dep);
+            content.push_str(&dep);
         }
 
-        cargo_toml_file.write_all(content.as_bytes()).map_err(|err| UntRustedError::IoError {
-   resource: format!("{:?}", cargo_toml_path.as_ref()),
-   err,
-   })?;
-
-        return Ok(());
+        return content;
     }
 
-    fn write_rust_code_to_cargo_dir<P: AsRef<Path>>(&self, cargo_src_path: P) -> Result<()> {
+    /// Returns where the generated code came from in the untrusted code (to locate compiler diagnostics), and the exported functions
+    fn write_rust_code_to_cargo_dir<P: AsRef<Path>>(&self, cargo_src_path: P) -> Result<SourceMap> {
+        // the source map keeps plain lines and columns, so the spans are not needed past this function
+        let _invalidate_spans = InvalidateSpansOnDrop;
 
         debug!("write rust code to cargo dir: {:?}", cargo_src_path.as_ref());
 
         let mut rust_code = self.rust_code.clone();
 
         // add exported type defs
-        for (_, typedef) in &self.exported_host_types {
+        for typedef in self.exported_host_types.values() {
             rust_code.push('\n');
             rust_code.push_str("#[derive(Debug, serde::Serialize, serde::Deserialize)]\n");
             rust_code.push_str(typedef);
         }
 
+        // add exported trait defs, which the untrusted code implements
+        let mut traitdefs: Vec<&String> = self.exported_host_traits.values().collect();
+        traitdefs.sort();
+        for traitdef in &traitdefs {
+            rust_code.push('\n');
+            rust_code.push_str(traitdef);
+        }
+
         let mut ast: syn::File = syn::parse_file(&rust_code)?;
 
-        // add validator
+        // reject anything that rustc would resolve against the host while building, and then anything the configured policies reject
+        let mut violations = policy::check_host_access(&ast);
+        for source_policy in &self.policies {
+            violations.extend(source_policy.check(&ast));
+        }
+
+        if !violations.is_empty() {
+            return Err(UntRustedError::PolicyViolation(violations));
+        }
 
         // update the ast
-        let use_extism_item = syn::Item::Use(syn::ItemUse {
-            attrs: Vec::new(),
-            vis: syn::Visibility::Inherited,
-            use_token: Token![use](Span::call_site()),
-            leading_colon: None,
-            tree: syn::UseTree::Path(syn::UsePath {
-                ident: syn::Ident::new("extism_pdk", Span::call_site()),
-                colon2_token: Token![::](Span::call_site()),
-                tree: Box::new(syn::UseTree::Glob(syn::UseGlob {
-                    star_token: Token![*](Span::call_site()),
-                })),
-            }),
-            semi_token: Token![;](Span::call_site()),
-        });
-        ast.items.insert(0, use_extism_item);
+        ast.items.insert(0, Self::create_use_extism_item());
 
         debug!("added use extism");
 
+        // the exported host types are appended at the crate root
         let mut jsonify_typenames = HashSet::new();
-        for (typename, _) in &self.exported_host_types {
-            jsonify_typenames.insert(typename.clone());
+        for typename in self.exported_host_types.keys() {
+            jsonify_typenames.insert(format!("crate::{}", typename));
         }
 
         for typename in &self.sdk_types {
             jsonify_typenames.insert(typename.clone());
         }
 
-        Self::tag_functions_for_export(&mut ast.items, "", &jsonify_typenames)?;
+        let path_resolver = PathResolver::new(&ast.items, jsonify_typenames);
+
+        let mut exports = Vec::new();
+        Self::tag_functions_for_export(&mut ast.items, "", &path_resolver, &self.export_policy, &mut exports)?;
+
+        for traitdef in traitdefs {
+            let trait_items = host_trait::create_guest_items(traitdef, &ast.items, &path_resolver, &mut exports)?;
+            ast.items.extend(trait_items);
+        }
+
+        let problems = exports::check_declared_exports(&self.sorted_declared_exports(), &exports);
+        if !problems.is_empty() {
+            return Err(UntRustedError::InvalidExports(problems));
+        }
+
+        // after tagging, so that the host function stubs are never exported
+        ast.items.extend(host_function::create_guest_items(&self.sorted_host_function_signatures())?);
+
+        ast.items.push(host_function::create_report_item()?);
+
+        if let Some(max_heap_bytes) = self.max_heap_bytes {
+            ast.items.push(Self::create_heap_limiter_item(max_heap_bytes)?);
+
+            debug!("added heap limiter");
+        }
+
+        ast.items.push(Self::create_panic_hook_item()?);
+
+        let host_typenames: HashSet<String> = self.exported_host_types.keys().cloned().collect();
+        for export in &mut exports {
+            export.find_host_types(&host_typenames);
+        }
+
+        // read back by CompiledUntrustedRustProject::exports, so that the wasm describes itself
+        ast.items.push(exports::create_metadata_item(&exports)?);
 
         debug!("start unparse of ast");
 
         let new_rust_code = prettyplease::unparse(&ast);
+        let source_map = SourceMap::new(&ast, &new_rust_code, self.rust_code.len())?;
 
         debug!("write new rust code to lib.rs");
 
@@ -364,11 +743,12 @@ impl UntrustedRustProject {
 
         debug!("done");
 
-        return Ok(());
+        return Ok(source_map);
     }
 
-    fn tag_functions_for_export(items: &mut Vec<syn::Item>, mod_names: &str, jsonify_typenames: &HashSet<String>) -> Result<()> {
-        debug!("start tag functions for export (mod_names={}, jsonify_typenames={:?})", mod_names, jsonify_typenames);
+    fn tag_functions_for_export(items: &mut Vec<syn::Item>, mod_names: &str, path_resolver: &PathResolver, export_policy: &ExportPolicy,
+                                exports: &mut Vec<ExportedFunction>) -> Result<()> {
+        debug!("start tag functions for export (mod_names={})", mod_names);
 
         let mut item_idx: usize = 0;
         while item_idx < items.len() {
@@ -386,121 +766,37 @@ impl UntrustedRustProject {
                         format!("{}__{}", mod_names, item_mod_name)
                     };
 
-                    Self::tag_functions_for_export(&mut content.1, &new_mod_names, jsonify_typenames)?;
+                    // the exported fn wrappers in this module need the extism macros and types too
+                    content.1.insert(0, Self::create_use_extism_item());
+
+                    Self::tag_functions_for_export(&mut content.1, &new_mod_names, path_resolver, export_policy, exports)?;
                 },
                 syn::Item::Fn(item_fn) => {
-                    if item_fn.vis != syn::Visibility::Public(Token![pub](Span::call_site())) {
-                        continue;
-                    }
-
-                    // export it by creating a clone of the function
-                    let new_fn_name = format!("{}__{}", mod_names, item_fn.sig.ident.to_string());
-
-                    let mut new_fn_sig = item_fn.sig.clone();
-                    new_fn_sig.ident = syn::Ident::new(&new_fn_name, Span::call_site());
-
-                    // jsonify the input params of the new function
-                    for param in &mut new_fn_sig.inputs {
-                        match param {
-                            syn::FnArg::Typed(pat_type) => {
-                                if Self::can_jsonify_type(jsonify_typenames, &pat_type.ty) {
-                                    pat_type.pat = Box::new(syn::Pat::TupleStruct(syn::PatTupleStruct {
-                                        attrs: Vec::new(),
-                                        qself: None,
-                                        path: Self::create_simple_path(&["Json"]),
-                                        paren_token: Paren::default(),
-                                        elems: {
-                                            let mut elems = Punctuated::new();
-                                            elems.push((*pat_type.pat).clone());
-                                            elems
-                                        }
-                                    }));
-
-                                    pat_type.ty = Box::new(Self::wrap_type("Json", &[&pat_type.ty]));
-                                }
-                            },
-                            _ => continue,
-                        }
-                    }
-
-                    // jsonify the return type of the new function
-                    let can_jsonify_ret_ty = match &item_fn.sig.output {
-                        syn::ReturnType::Type(_, ty) => {
-                            let can_jsonify_ret_ty = Self::can_jsonify_type(jsonify_typenames, ty);
-                            let new_ret_ty = if can_jsonify_ret_ty {
-                                let new_ret_ty = Self::wrap_type("Json", &[ty]);
-                                Self::wrap_type("FnResult", &[&new_ret_ty])
-                            } else {
-                                Self::wrap_type("FnResult", &[ty])
-                            };
-
-                            new_fn_sig.output = syn::ReturnType::Type(Token![->](Span::call_site()), Box::new(new_ret_ty));
-                            can_jsonify_ret_ty
-                        },
-                        _ => false,
+                    let fn_path = if mod_names.is_empty() {
+                        item_fn.sig.ident.to_string()
+                    } else {
+                        format!("{}::{}", mod_names.replace("__", "::"), item_fn.sig.ident)
                     };
 
-                    let mut call_old_fn_args = Punctuated::new();
-                    for param in &item_fn.sig.inputs {
-                        match param {
-                            syn::FnArg::Typed(pat_type) => {
-                                let param_name: String = Self::get_param_name(pat_type)?;
-
-                                call_old_fn_args.push(syn::Expr::Path(syn::ExprPath {
-                                    attrs: Vec::new(),
-                                    qself: None,
-                                    path: Self::create_simple_path(&[&param_name]),
-                                }));
-                            },
-                            _ => return Err(UntRustedError::UnsupportedFnArg(format!("{:?}", param))),
-                        }
-                    }
-
-                    let old_fn_call = syn::Expr::Call(syn::ExprCall {
-                        attrs: Vec::new(),
-                        func: Box::new(syn::Expr::Path(syn::ExprPath {
-                            attrs: Vec::new(),
-                            qself: None,
-                            path: syn::Path {
-                                leading_colon: None,
-                                segments: {
-                                    let mut segments = Punctuated::new();
-                                    segments.push(syn::PathSegment {
-                                        ident: item_fn.sig.ident.clone(),
-                                        arguments: syn::PathArguments::None,
-                                    });
-                                    segments
-                                },
-                            }
-                        })),
-                        paren_token: Paren::default(),
-                        args: call_old_fn_args,
-                    });
+                    // always strip the tag, since it is not a real attribute
+                    let is_tagged = Self::take_export_attr(&mut item_fn.attrs);
 
-                    let ok_wrapper_call = if can_jsonify_ret_ty {
-                        let json_wrapper_call_expr = Self::create_call_expr("Json", &[&old_fn_call]);
-                        syn::Stmt::Expr(Self::create_call_expr("Ok", &[&json_wrapper_call_expr]), None)
-                    } else {
-                        syn::Stmt::Expr(Self::create_call_expr("Ok", &[&old_fn_call]), None)
+                    let is_exported = match export_policy {
+                        ExportPolicy::AllPublic => matches!(item_fn.vis, syn::Visibility::Public(_)),
+                        ExportPolicy::Tagged => is_tagged,
+                        ExportPolicy::Allowlist(fn_paths) => fn_paths.contains(&fn_path),
                     };
 
-                    let mut new_fn_attrs = item_fn.attrs.clone();
-                    new_fn_attrs.push(syn::Attribute {
-                        pound_token: Token![#](Span::call_site()),
-                        style: syn::AttrStyle::Outer,
-                        bracket_token: Bracket::default(),
-                        meta: syn::Meta::Path(Self::create_simple_path(&["plugin_fn"])),
-                    });
+                    if !is_exported {
+                        debug!("not exporting fn {}", fn_path);
+                        item_idx += 1;
+                        continue;
+                    }
 
-                    let new_fn_item = syn::Item::Fn(syn::ItemFn {
-                        attrs: new_fn_attrs,
-                        vis: item_fn.vis.clone(),
-                        sig: new_fn_sig,
-                        block: Box::new(syn::Block {
-                            brace_token: item_fn.block.brace_token.clone(),
-                            stmts: vec![ok_wrapper_call],
-                        }),
-                    });
+                    // export it by creating a clone of the function
+                    let (new_fn, export) = Self::create_exported_fn(item_fn, &fn_path, mod_names, path_resolver)?;
+                    let new_fn_item = syn::Item::Fn(new_fn);
+                    exports.push(export);
 
                     items.insert(item_idx + 1, new_fn_item);
                     item_idx += 1;
@@ -515,73 +811,445 @@ impl UntrustedRustProject {
         return Ok(());
     }
 
-    fn can_jsonify_type(jsonify_typenames: &HashSet<String>, ty: &syn::Type) -> bool {
-        match ty {
-            syn::Type::Path(syn::TypePath { path, .. }) => {
-                let path_as_str = path.to_token_stream().to_string();
+    /// Creates a `#[plugin_fn]` wrapper around `item_fn` that jsonifies the params and return type as needed
+    fn create_exported_fn(item_fn: &syn::ItemFn, fn_path: &str, mod_names: &str, path_resolver: &PathResolver) -> Result<(syn::ItemFn, ExportedFunction)> {
+        // export it by creating a clone of the function
+        let new_fn_name = format!("{}__{}", mod_names, item_fn.sig.ident);
+
+        // the types in the signature are resolved from the module that the function is in
+        let mod_path: Vec<String> = mod_names.split("__").filter(|mod_name| !mod_name.is_empty()).map(String::from).collect();
+
+        let mut new_fn_sig = item_fn.sig.clone();
+        new_fn_sig.ident = syn::Ident::new(&new_fn_name, Span::call_site());
+
+        // the new function has to own its params, so borrowed params are deserialized as owned values and borrowed again for the call
+        let mut owned_params = Vec::new();
+        let mut call_old_fn_args = Punctuated::new();
+        for param in &item_fn.sig.inputs {
+            match param {
+                syn::FnArg::Typed(pat_type) => {
+                    let param_name = syn::Ident::new(&Self::get_param_name(pat_type)?, Span::call_site());
+                    let (owned_param, borrow) = Self::create_owned_param(pat_type)?;
 
-                jsonify_typenames.contains(&path_as_str)
+                    owned_params.push(owned_param);
+                    call_old_fn_args.push(exports::borrow_arg(&param_name, borrow));
+                },
+                _ => return Err(UntRustedError::UnsupportedFnArg(format!("{:?}", param))),
             }
-            _ => false,
         }
-    }
 
-    fn create_simple_path(pathname: &[&str]) -> syn::Path {
-        syn::Path {
-            leading_colon: None,
-            segments: {
-                let mut segments = Punctuated::new();
-                for segment in pathname {
-                    segments.push(syn::PathSegment {
-                        ident: syn::Ident::new(segment, Span::call_site()),
-                        arguments: syn::PathArguments::None,
-                    });
+        let mut input_encoding = Encoding::Raw;
+        if owned_params.len() > 1 {
+            // extism plugin functions only take a single input, so pack all of the params into a json tuple
+            new_fn_sig.inputs = Self::pack_params_into_tuple(&owned_params);
+            input_encoding = Encoding::JsonTuple;
+        } else {
+            // jsonify the input params of the new function
+            new_fn_sig.inputs = Punctuated::new();
+            for mut pat_type in owned_params {
+                if Self::can_jsonify_type(path_resolver, &mod_path, &pat_type.ty) {
+                    *pat_type.pat = Self::wrap_pat("Json", &pat_type.pat);
+                    *pat_type.ty = Self::wrap_type("Json", &[&pat_type.ty]);
+                    input_encoding = Encoding::Json;
                 }
-                segments
-            },
+                new_fn_sig.inputs.push(syn::FnArg::Typed(pat_type));
+            }
         }
-    }
 
-    fn create_call_expr(fn_name: &str, args: &[&syn::Expr]) -> syn::Expr {
-        syn::Expr::Call(syn::ExprCall {
+        // a `Result` with a serializable error is unwrapped, so that `Err` reaches the host as a guest error instead of as the output
+        let guest_result = match &item_fn.sig.output {
+            syn::ReturnType::Type(_, ty) => Self::result_type_args(ty)
+                .filter(|(_, err_ty)| Self::is_serializable_type(path_resolver, &mod_path, err_ty)),
+            syn::ReturnType::Default => None,
+        };
+        let output = match guest_result {
+            Some((ok_ty, _)) => syn::ReturnType::Type(Token![->](Span::call_site()), Box::new(ok_ty.clone())),
+            None => item_fn.sig.output.clone(),
+        };
+
+        // jsonify the return type of the new function
+        let can_jsonify_ret_ty = match &output {
+            syn::ReturnType::Type(_, ty) => {
+                let can_jsonify_ret_ty = Self::can_jsonify_type(path_resolver, &mod_path, ty);
+                let new_ret_ty = if can_jsonify_ret_ty {
+                    let new_ret_ty = Self::wrap_type("Json", &[ty]);
+                    Self::wrap_type("FnResult", &[&new_ret_ty])
+                } else {
+                    Self::wrap_type("FnResult", &[ty])
+                };
+
+                new_fn_sig.output = syn::ReturnType::Type(Token![->](Span::call_site()), Box::new(new_ret_ty));
+                can_jsonify_ret_ty
+            },
+            syn::ReturnType::Default => {
+                // plugin_fn always needs a return type, so `fn foo()` becomes `fn foo() -> FnResult<()>`
+                let unit_ty = syn::Type::Tuple(syn::TypeTuple {
+                    paren_token: Paren::default(),
+                    elems: Punctuated::new(),
+                });
+
+                new_fn_sig.output = syn::ReturnType::Type(Token![->](Span::call_site()), Box::new(Self::wrap_type("FnResult", &[&unit_ty])));
+                false
+            },
+        };
+
+        let old_fn_call = syn::Expr::Call(syn::ExprCall {
             attrs: Vec::new(),
             func: Box::new(syn::Expr::Path(syn::ExprPath {
                 attrs: Vec::new(),
                 qself: None,
-                path: Self::create_simple_path(&[fn_name]),
+                path: syn::Path {
+                    leading_colon: None,
+                    segments: {
+                        let mut segments = Punctuated::new();
+                        segments.push(syn::PathSegment {
+                            ident: item_fn.sig.ident.clone(),
+                            arguments: syn::PathArguments::None,
+                        });
+                        segments
+                    },
+                }
             })),
             paren_token: Paren::default(),
-            args: {
-                let mut new_args = Punctuated::new();
-                for arg in args {
-                    new_args.push((*arg).clone());
-                }
-                new_args
+            args: call_old_fn_args,
+        });
+
+        let ok_wrapper_call = match (guest_result, can_jsonify_ret_ty) {
+            (Some(_), _) => {
+                let report_module = syn::Ident::new(GUEST_REPORT_FN_NAME, Span::call_site());
+                let output_expr: syn::Expr = if can_jsonify_ret_ty { syn::parse_quote!(Json(output)) } else { syn::parse_quote!(output) };
+                syn::Stmt::Expr(syn::parse_quote! {
+                    match #old_fn_call {
+                        Ok(output) => Ok(#output_expr),
+                        Err(err) => {
+                            crate::#report_module::report(format!("{}{}", #GUEST_ERROR_MSG, extism_pdk::json::to_string(&err)?).as_bytes());
+                            Err(extism_pdk::Error::msg("the exported function returned Err").into())
+                        },
+                    }
+                }, None)
+            },
+            (None, true) => {
+                let json_wrapper_call_expr = Self::create_call_expr("Json", &[&old_fn_call]);
+                syn::Stmt::Expr(Self::create_call_expr("Ok", &[&json_wrapper_call_expr]), None)
+            },
+            (None, false) => syn::Stmt::Expr(Self::create_call_expr("Ok", &[&old_fn_call]), None),
+        };
+
+        let mut new_fn_attrs = item_fn.attrs.clone();
+        new_fn_attrs.push(syn::Attribute {
+            pound_token: Token![#](Span::call_site()),
+            style: syn::AttrStyle::Outer,
+            bracket_token: Bracket::default(),
+            meta: syn::Meta::Path(Self::create_simple_path(&["plugin_fn"])),
+        });
+
+        let output_encoding = if can_jsonify_ret_ty { Encoding::Json } else { Encoding::Raw };
+        // record the types the way the host knows them, rather than through the aliases of the untrusted code
+        let resolved_sig = path_resolver.resolve_signature(&mod_path, &item_fn.sig);
+        let mut export = ExportedFunction::new(fn_path.to_string(), new_fn_name, &item_fn.attrs, &resolved_sig.inputs, &resolved_sig.output,
+                                               input_encoding, output_encoding);
+        if let (Some(_), syn::ReturnType::Type(_, resolved_ty)) = (guest_result, &resolved_sig.output) {
+            if let Some((ok_ty, err_ty)) = Self::result_type_args(resolved_ty) {
+                export.return_type = Some(exports::type_to_string(ok_ty));
+                export.error_type = Some(exports::type_to_string(err_ty));
             }
-        })
+        }
+
+        let new_fn = syn::ItemFn {
+            attrs: new_fn_attrs,
+            // plugin_fn requires the exported fn to be public, even if the original is not
+            vis: syn::Visibility::Public(Token![pub](Span::call_site())),
+            sig: new_fn_sig,
+            block: Box::new(syn::Block {
+                brace_token: item_fn.block.brace_token,
+                stmts: vec![Self::create_install_panic_hook_stmt(), ok_wrapper_call],
+            }),
+        };
+
+        return Ok((new_fn, export));
     }
 
-    fn wrap_type(outer_type: &str, inner_types: &[&syn::Type]) -> syn::Type {
-        syn::Type::Path(syn::TypePath {
-            qself: None,
-            path: syn::Path {
-                leading_colon: None,
-                segments: {
-                    let mut segments = Punctuated::new();
-                    segments.push(syn::PathSegment {
-                        ident: syn::Ident::new(outer_type, Span::call_site()),
-                        arguments: syn::PathArguments::AngleBracketed(syn::AngleBracketedGenericArguments {
-                            colon2_token: None,
-                            lt_token: Token![<](Span::call_site()),
-                            args: {
-                                let mut args = Punctuated::new();
-                                for inner_type in inner_types {
-                                    args.push(syn::GenericArgument::Type((*inner_type).clone()));
-                                }
-                                args
-                            },
-                            gt_token: Token![>](Span::call_site()),
-                        })
+    /// Removes any `#[export]` attributes, returning true if there were any
+    fn take_export_attr(attrs: &mut Vec<syn::Attribute>) -> bool {
+        let num_attrs = attrs.len();
+        attrs.retain(|attr| !attr.path().is_ident("export"));
+        return attrs.len() != num_attrs;
+    }
+
+    /// Replaces a borrowed param type with its owned type (see [`exports::owned_type`]), making the binding `mut` if it gets borrowed mutably
+    fn create_owned_param(pat_type: &syn::PatType) -> Result<(syn::PatType, exports::Borrow)> {
+        let (owned_ty, borrow) = exports::owned_type(&pat_type.ty)?;
+
+        let mut owned_param = pat_type.clone();
+        *owned_param.ty = owned_ty;
+        if let (syn::Pat::Ident(pat_ident), exports::Borrow::Mut) = (owned_param.pat.as_mut(), borrow) {
+            pat_ident.mutability = Some(Token![mut](Span::call_site()));
+        }
+
+        return Ok((owned_param, borrow));
+    }
+
+    /// Turns `(a: A, b: B)` into `(Json((a, b)): Json<(A, B)>)`
+    fn pack_params_into_tuple(params: &[syn::PatType]) -> Punctuated<syn::FnArg, Token![,]> {
+        let mut tuple_pats = Punctuated::new();
+        let mut tuple_tys = Punctuated::new();
+        for pat_type in params {
+            tuple_pats.push((*pat_type.pat).clone());
+            tuple_tys.push((*pat_type.ty).clone());
+        }
+
+        let tuple_pat = syn::Pat::Tuple(syn::PatTuple {
+            attrs: Vec::new(),
+            paren_token: Paren::default(),
+            elems: tuple_pats,
+        });
+
+        let tuple_ty = syn::Type::Tuple(syn::TypeTuple {
+            paren_token: Paren::default(),
+            elems: tuple_tys,
+        });
+
+        let mut packed_inputs = Punctuated::new();
+        packed_inputs.push(syn::FnArg::Typed(syn::PatType {
+            attrs: Vec::new(),
+            pat: Box::new(Self::wrap_pat("Json", &tuple_pat)),
+            colon_token: Token![:](Span::call_site()),
+            ty: Box::new(Self::wrap_type("Json", &[&tuple_ty])),
+        }));
+
+        return packed_inputs;
+    }
+
+    /// The `T` and `E` of a `Result<T, E>`
+    fn result_type_args(ty: &syn::Type) -> Option<(&syn::Type, &syn::Type)> {
+        let syn::Type::Path(syn::TypePath { qself: None, path }) = ty else {
+            return None;
+        };
+
+        let last_segment = path.segments.last().filter(|segment| segment.ident == "Result")?;
+        let syn::PathArguments::AngleBracketed(generic_args) = &last_segment.arguments else {
+            return None;
+        };
+
+        return match (generic_args.args.first(), generic_args.args.iter().nth(1), generic_args.args.len()) {
+            (Some(syn::GenericArgument::Type(ok_ty)), Some(syn::GenericArgument::Type(err_ty)), 2) => Some((ok_ty, err_ty)),
+            _ => None,
+        };
+    }
+
+    /// True for types that have to be passed as json: serializable types that extism cannot pass as raw bytes.
+    /// Params should be made owned first (see [`exports::owned_type`])
+    fn can_jsonify_type(path_resolver: &PathResolver, mod_path: &[String], ty: &syn::Type) -> bool {
+        return !exports::is_raw_type(ty) && Self::is_serializable_type(path_resolver, mod_path, ty);
+    }
+
+    /// True for the registered types, and anything made of serializable pieces, e.g. `Vec<Inputs>`, `Option<(Inputs, i32)>` or `HashMap<String, Inputs>`
+    fn is_serializable_type(path_resolver: &PathResolver, mod_path: &[String], ty: &syn::Type) -> bool {
+        match ty {
+            syn::Type::Path(syn::TypePath { qself: None, path }) => {
+                if path_resolver.is_known_type(mod_path, path) {
+                    return true;
+                }
+
+                let Some(last_segment) = path.segments.last() else {
+                    return false;
+                };
+
+                let type_name = last_segment.ident.to_string();
+                match &last_segment.arguments {
+                    syn::PathArguments::None => SERIALIZABLE_PRIMITIVES.contains(&type_name.as_str()),
+                    syn::PathArguments::AngleBracketed(generic_args) => {
+                        SERIALIZABLE_CONTAINERS.contains(&type_name.as_str()) && generic_args.args.iter().all(|generic_arg| match generic_arg {
+                            syn::GenericArgument::Type(ty) => Self::is_serializable_type(path_resolver, mod_path, ty),
+                            _ => false,
+                        })
+                    },
+                    syn::PathArguments::Parenthesized(_) => false,
+                }
+            },
+            syn::Type::Tuple(type_tuple) => type_tuple.elems.iter().all(|elem| Self::is_serializable_type(path_resolver, mod_path, elem)),
+            syn::Type::Array(type_array) => Self::is_serializable_type(path_resolver, mod_path, &type_array.elem),
+            syn::Type::Paren(type_paren) => Self::is_serializable_type(path_resolver, mod_path, &type_paren.elem),
+            syn::Type::Group(type_group) => Self::is_serializable_type(path_resolver, mod_path, &type_group.elem),
+            _ => false,
+        }
+    }
+
+    /// Creates a module with a global allocator that refuses to go over `max_heap_bytes`.
+    /// When it does refuse, it reports to the host (see [`host_function::create_report_item`]) so that the host can tell why the guest trapped
+    fn create_heap_limiter_item(max_heap_bytes: usize) -> Result<syn::Item> {
+        let report_module = syn::Ident::new(GUEST_REPORT_FN_NAME, Span::call_site());
+        let item = quote::quote! {
+            mod __unt_rust_ed_heap_limiter {
+                use std::alloc::{GlobalAlloc, Layout, System};
+                use std::sync::atomic::{AtomicUsize, Ordering};
+
+                const MAX_HEAP_BYTES: usize = #max_heap_bytes;
+
+                static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+                struct HeapLimiter;
+
+                unsafe impl GlobalAlloc for HeapLimiter {
+                    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                        let requested_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed).saturating_add(layout.size());
+                        if requested_bytes > MAX_HEAP_BYTES {
+                            report_heap_limit_exceeded(requested_bytes);
+                            return std::ptr::null_mut();
+                        }
+
+                        let ptr = System.alloc(layout);
+                        if !ptr.is_null() {
+                            ALLOCATED_BYTES.store(requested_bytes, Ordering::Relaxed);
+                        }
+                        ptr
+                    }
+
+                    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                        System.dealloc(ptr, layout);
+                        ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+                    }
+                }
+
+                // the heap is full, so the message has to be formatted on the stack
+                struct StackBuf {
+                    bytes: [u8; 128],
+                    len: usize,
+                }
+
+                impl std::fmt::Write for StackBuf {
+                    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                        let end = self.len + s.len();
+                        if end > self.bytes.len() {
+                            return Err(std::fmt::Error);
+                        }
+                        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+                        self.len = end;
+                        Ok(())
+                    }
+                }
+
+                fn report_heap_limit_exceeded(requested_bytes: usize) {
+                    let mut buf = StackBuf { bytes: [0; 128], len: 0 };
+                    if std::fmt::Write::write_fmt(&mut buf, format_args!("{} requested={} allowed={}", #HEAP_LIMIT_EXCEEDED_MSG, requested_bytes, MAX_HEAP_BYTES)).is_ok() {
+                        crate::#report_module::report(&buf.bytes[..buf.len]);
+                    }
+                }
+
+                #[global_allocator]
+                static GLOBAL: HeapLimiter = HeapLimiter;
+            }
+        };
+
+        return Ok(syn::parse2(item)?);
+    }
+
+    /// Creates a module with a panic hook that reports the message and location, so that the host can tell a panic from other traps
+    fn create_panic_hook_item() -> Result<syn::Item> {
+        let panic_hook_module = syn::Ident::new(PANIC_HOOK_MODULE_NAME, Span::call_site());
+        let report_module = syn::Ident::new(GUEST_REPORT_FN_NAME, Span::call_site());
+        let item = quote::quote! {
+            mod #panic_hook_module {
+                pub fn install() {
+                    static INSTALL: std::sync::Once = std::sync::Once::new();
+                    INSTALL.call_once(|| std::panic::set_hook(Box::new(|info| {
+                        let message = match (info.payload().downcast_ref::<&str>(), info.payload().downcast_ref::<String>()) {
+                            (Some(message), _) => message.to_string(),
+                            (_, Some(message)) => message.clone(),
+                            _ => String::from("Box<dyn Any>"),
+                        };
+                        let (line, column) = info.location().map(|location| (location.line(), location.column())).unwrap_or((0, 0));
+
+                        if let Ok(report) = extism_pdk::json::to_string(&(message, line, column)) {
+                            crate::#report_module::report(format!("{}{}", #GUEST_PANIC_MSG, report).as_bytes());
+                        }
+                    })));
+                }
+            }
+        };
+
+        return Ok(syn::parse2(item)?);
+    }
+
+    fn create_install_panic_hook_stmt() -> syn::Stmt {
+        let panic_hook_module = syn::Ident::new(PANIC_HOOK_MODULE_NAME, Span::call_site());
+        syn::parse_quote!(crate::#panic_hook_module::install();)
+    }
+
+    /// `use extism_pdk::*;`
+    fn create_use_extism_item() -> syn::Item {
+        syn::Item::Use(syn::ItemUse {
+            attrs: Vec::new(),
+            vis: syn::Visibility::Inherited,
+            use_token: Token![use](Span::call_site()),
+            leading_colon: None,
+            tree: syn::UseTree::Path(syn::UsePath {
+                ident: syn::Ident::new("extism_pdk", Span::call_site()),
+                colon2_token: Token![::](Span::call_site()),
+                tree: Box::new(syn::UseTree::Glob(syn::UseGlob {
+                    star_token: Token![*](Span::call_site()),
+                })),
+            }),
+            semi_token: Token![;](Span::call_site()),
+        })
+    }
+
+    fn create_simple_path(pathname: &[&str]) -> syn::Path {
+        syn::Path {
+            leading_colon: None,
+            segments: {
+                let mut segments = Punctuated::new();
+                for segment in pathname {
+                    segments.push(syn::PathSegment {
+                        ident: syn::Ident::new(segment, Span::call_site()),
+                        arguments: syn::PathArguments::None,
+                    });
+                }
+                segments
+            },
+        }
+    }
+
+    fn create_call_expr(fn_name: &str, args: &[&syn::Expr]) -> syn::Expr {
+        syn::Expr::Call(syn::ExprCall {
+            attrs: Vec::new(),
+            func: Box::new(syn::Expr::Path(syn::ExprPath {
+                attrs: Vec::new(),
+                qself: None,
+                path: Self::create_simple_path(&[fn_name]),
+            })),
+            paren_token: Paren::default(),
+            args: {
+                let mut new_args = Punctuated::new();
+                for arg in args {
+                    new_args.push((*arg).clone());
+                }
+                new_args
+            }
+        })
+    }
+
+    fn wrap_type(outer_type: &str, inner_types: &[&syn::Type]) -> syn::Type {
+        syn::Type::Path(syn::TypePath {
+            qself: None,
+            path: syn::Path {
+                leading_colon: None,
+                segments: {
+                    let mut segments = Punctuated::new();
+                    segments.push(syn::PathSegment {
+                        ident: syn::Ident::new(outer_type, Span::call_site()),
+                        arguments: syn::PathArguments::AngleBracketed(syn::AngleBracketedGenericArguments {
+                            colon2_token: None,
+                            lt_token: Token![<](Span::call_site()),
+                            args: {
+                                let mut args = Punctuated::new();
+                                for inner_type in inner_types {
+                                    args.push(syn::GenericArgument::Type((*inner_type).clone()));
+                                }
+                                args
+                            },
+                            gt_token: Token![>](Span::call_site()),
+                        })
                     });
                     segments
                 }
@@ -589,6 +1257,20 @@ impl UntrustedRustProject {
         })
     }
 
+    fn wrap_pat(outer_type: &str, inner_pat: &syn::Pat) -> syn::Pat {
+        syn::Pat::TupleStruct(syn::PatTupleStruct {
+            attrs: Vec::new(),
+            qself: None,
+            path: Self::create_simple_path(&[outer_type]),
+            paren_token: Paren::default(),
+            elems: {
+                let mut elems = Punctuated::new();
+                elems.push(inner_pat.clone());
+                elems
+            }
+        })
+    }
+
     fn get_param_name(pat_type: &syn::PatType) -> Result<String> {
         let name = match pat_type.pat.deref() {
             syn::Pat::Ident(pat_ident) => {
@@ -600,24 +1282,38 @@ impl UntrustedRustProject {
         return Ok(name);
     }
 
-    fn cargo_build_to_wasm<P: AsRef<Path>>(&self, cargo_dir: P) -> Result<PathBuf> {
+    fn cargo_build_to_wasm<P: AsRef<Path>>(&self, cargo_dir: P, source_map: &SourceMap) -> Result<(PathBuf, Vec<Diagnostic>)> {
         debug!("start cargo build to wasm (dir={:?})", cargo_dir.as_ref());
 
-        let cargo_output = Command::new("cargo")
-            .args(["build", "--target", self.target.as_str(), "--release"])
-            .current_dir(&cargo_dir)
-            .output().map_err(|err| UntRustedError::IoError {
-   resource: "cargo build".into(),
-   err,
-   })?;
+        let diagnostics = self.run_cargo_for_target(&["build", "--release"], cargo_dir.as_ref(), source_map)?;
+
+        debug!("cargo build was a success");
+        return Ok((cargo_dir.as_ref().join("target").join(self.target.as_str()).join("release/test_wasm.wasm"), diagnostics));
+    }
+
+    /// Runs `cargo <args>` for the wasm target, returning the warnings or failing with [`UntRustedError::CompileError`] if there are errors
+    fn run_cargo_for_target(&self, args: &[&str], cargo_dir: &Path, source_map: &SourceMap) -> Result<Vec<Diagnostic>> {
+        let mut cargo_command = Command::new("cargo");
+        cargo_command
+            .args(args)
+            .args(["--target", self.target.as_str(), "--message-format=json"])
+            .current_dir(cargo_dir);
+
+        if let Some(build_sandbox) = &self.build_sandbox {
+            build_sandbox.apply(&mut cargo_command, cargo_dir)?;
+        }
 
-        debug!("cargo build completed, output: {:?}", cargo_output);
+        let cargo_output = run_cargo(cargo_command, self.compile_timeout)?;
+
+        debug!("cargo {:?} completed, output: {:?}", args, cargo_output);
 
         // parse cargo output, find target
         //println!("cargo build output:\n{:?}", cargo_output);
 
+        let diagnostics = parse_cargo_messages(&String::from_utf8_lossy(&cargo_output.stdout), source_map);
+
         if !cargo_output.status.success() {
-            debug!("cargo build failed");
+            debug!("cargo {:?} failed", args);
 
             let stdout_str = String::from_utf8_lossy(&cargo_output.stdout);
             let stderr_str = String::from_utf8_lossy(&cargo_output.stderr);
@@ -627,12 +1323,15 @@ impl UntrustedRustProject {
                 return Err(UntRustedError::MissingCargoTargetInstallation(self.target.as_str().into()));
             }
 
+            if diagnostics.iter().any(|diagnostic| diagnostic.level == DiagnosticLevel::Error) {
+                return Err(UntRustedError::CompileError(diagnostics));
+            }
+
             // unknown error
             return Err(UntRustedError::UnknownCargoError(stdout_str.into(), stderr_str.into()));
         }
 
-        debug!("cargo build was a success");
-        return Ok(cargo_dir.as_ref().join("target").join(self.target.as_str()).join("release/test_wasm.wasm"));
+        return Ok(diagnostics);
     }
 }
 
@@ -640,23 +1339,201 @@ impl UntrustedRustProject {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CompiledUntrustedRustProject {
     project_hash: ProjectHash,
+    cache_key: CompileCacheKey,
     manifest: Manifest,
+    #[serde(default)]
+    fuel_limit: Option<u64>,
     target: WasmCompileTarget,
+    #[serde(default)]
+    diagnostics: Vec<Diagnostic>,
+    /// locates guest panics in the untrusted code
+    #[serde(default)]
+    source_map: SourceMap,
+    /// read from the metadata section of the wasm once, when the project is built
+    exports: Vec<ExportedFunction>,
+    #[serde(skip)]
+    host_functions: Vec<(String, HostFunctionCall)>,
+    /// see [`UntrustedRustProject::with_native_code_caching`]
+    #[serde(skip)]
+    native_code_dir: Option<PathBuf>,
 }
 
 impl CompiledUntrustedRustProject {
+    /// The inputs that this project was compiled from
+    pub fn cache_key(&self) -> &CompileCacheKey {
+        &self.cache_key
+    }
+
+    /// Warnings from compiling the untrusted code
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The exported functions, as described by the metadata section embedded in the wasm
+    pub fn exports(&self) -> &[ExportedFunction] {
+        &self.exports
+    }
+
     pub fn create_container(&self) -> Result<Container> {
-        let plugin = Plugin::new(&self.manifest, [], self.target == WasmCompileTarget::Wasi)?;
+        return Self::create_shared_container(&Arc::new(self.clone()));
+    }
+
+    /// Creates a container that shares the compiled project with others, e.g. the containers of a [`ContainerPool`]
+    pub(crate) fn create_shared_container(compiled: &Arc<Self>) -> Result<Container> {
+        let default_call_options = compiled.default_call_options();
+        let plugin_limits = PluginLimits::from(default_call_options);
+        let memory_limits = MemoryLimits::new(compiled.cache_key.max_heap_bytes.map(|num_bytes| num_bytes.saturating_add(HEAP_LIMIT_SLACK_BYTES)));
+        let compiled_plugin = compiled.compile_plugin(plugin_limits, &memory_limits)?;
         Ok(Container {
-            plugin,
+            plugin: Plugin::new_from_compiled(&compiled_plugin)?,
+            compiled_plugins: HashMap::from([(plugin_limits, compiled_plugin)]),
+            needs_reinstantiate: false,
+            plugin_limits,
+            memory_limits,
+            default_call_options,
+            watchdog: Watchdog::default(),
+            last_call_fuel: None,
+            output: Vec::new(),
+            compiled: Arc::clone(compiled),
         })
     }
+
+    /// Creates a pool of containers that can be shared between threads
+    pub fn create_pool(&self, pool_options: PoolOptions) -> Result<ContainerPool> {
+        return ContainerPool::new(Arc::new(self.clone()), pool_options);
+    }
+
+    /// The limits of the project, which containers start out with
+    pub(crate) fn default_call_options(&self) -> CallOptions {
+        return CallOptions {
+            timeout: self.manifest.timeout_ms.map(Duration::from_millis),
+            fuel: self.fuel_limit,
+            max_memory_bytes: self.manifest.memory.max_pages.map(|max_pages| max_pages as usize * WASM_PAGE_SIZE),
+            max_output_bytes: None,
+        };
+    }
+
+    fn compile_plugin(&self, plugin_limits: PluginLimits, memory_limits: &Arc<MemoryLimits>) -> Result<CompiledPlugin> {
+        if let Some(native_code_dir) = &self.native_code_dir {
+            let compiled_plugin = native_code_cache_config(native_code_dir)
+                .and_then(|config_path| Ok(self.plugin_builder(plugin_limits, memory_limits).with_cache_config(config_path).compile()?));
+
+            match compiled_plugin {
+                Ok(compiled_plugin) => return Ok(compiled_plugin),
+                Err(err) => warn!("unable to cache native code in {:?}, so compiling without it: {}", native_code_dir, err),
+            }
+        }
+
+        return Ok(self.plugin_builder(plugin_limits, memory_limits).compile()?);
+    }
+
+    fn plugin_builder(&self, plugin_limits: PluginLimits, memory_limits: &Arc<MemoryLimits>) -> PluginBuilder<'_> {
+        let functions = self.host_functions.iter()
+            .map(|(name, call)| host_function::create_extism_function(name, *call))
+            .chain(std::iter::once(host_function::create_report_function()));
+
+        // the container enforces the timeout and the memory limit itself, so that they can be changed per call
+        let mut manifest = self.manifest.clone();
+        manifest.timeout_ms = None;
+        manifest.memory.max_pages = None;
+
+        let plugin_builder = PluginBuilder::new(manifest)
+            .with_wasmtime_config(memory_limits.wasmtime_config())
+            .with_functions(functions)
+            .with_wasi(self.target == WasmCompileTarget::Wasi);
+
+        return if let Some(fuel_limit) = plugin_limits.fuel {
+            plugin_builder.with_fuel_limit(fuel_limit)
+        } else {
+            plugin_builder
+        };
+    }
+}
+
+/// Limits for a single call (see [`Container::call_with`]). A limit that is `None` falls back to the container's default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallOptions {
+    pub timeout: Option<Duration>,
+    /// see [`UntrustedRustProject::with_fuel_limit`]
+    pub fuel: Option<u64>,
+    /// see [`UntrustedRustProject::with_max_memory_bytes`]
+    pub max_memory_bytes: Option<usize>,
+    /// fails the call with [`UntRustedError::RuntimeExceededOutputSize`] if the untrusted code returns more bytes than this.
+    /// It is checked once the untrusted code has returned, so it does not stop the untrusted code from building a larger output
+    /// in its own memory. `max_memory_bytes` limits that
+    pub max_output_bytes: Option<usize>,
+}
+
+impl CallOptions {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    pub fn with_max_memory_bytes(mut self, num_bytes: usize) -> Self {
+        self.max_memory_bytes = Some(num_bytes);
+        self
+    }
+
+    pub fn with_max_output_bytes(mut self, num_bytes: usize) -> Self {
+        self.max_output_bytes = Some(num_bytes);
+        self
+    }
+
+    /// Fills in the limits that are not set from `defaults`
+    fn or(self, defaults: &CallOptions) -> CallOptions {
+        return CallOptions {
+            timeout: self.timeout.or(defaults.timeout),
+            fuel: self.fuel.or(defaults.fuel),
+            max_memory_bytes: self.max_memory_bytes.or(defaults.max_memory_bytes),
+            max_output_bytes: self.max_output_bytes.or(defaults.max_output_bytes),
+        };
+    }
+}
+
+/// The limits that are part of a compiled plugin, so that changing them takes another one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct PluginLimits {
+    fuel: Option<u64>,
+}
+
+impl From<CallOptions> for PluginLimits {
+    fn from(call_options: CallOptions) -> Self {
+        Self {
+            fuel: call_options.fuel,
+        }
+    }
 }
 
 pub struct Container {
     plugin: Plugin,
+    /// the untrusted code compiled for each of the limits used so far, since the limits are part of the compiled plugin.
+    /// Creates a fresh instance of the untrusted code after a call is interrupted or traps
+    compiled_plugins: HashMap<PluginLimits, CompiledPlugin>,
+    /// set when a call left `plugin` broken, until it has been re-instantiated
+    needs_reinstantiate: bool,
+    /// the limits that `plugin` was built with
+    plugin_limits: PluginLimits,
+    /// caps the memory of `plugin` (see [`CallOptions::max_memory_bytes`] and [`UntrustedRustProject::with_max_heap_bytes`])
+    memory_limits: Arc<MemoryLimits>,
+    default_call_options: CallOptions,
+    watchdog: Watchdog,
+    last_call_fuel: Option<u64>,
+    /// output of the last call, which the return value of [`Container::call`] may borrow from
+    output: Vec<u8>,
+    /// compiles the plugin for a different fuel limit, lists the exports and locates guest panics
+    compiled: Arc<CompiledUntrustedRustProject>,
 }
 
+// SAFETY: the only parts of the plugin that are not `Send` are the user data of the host functions,
+// which is always `()` (see `host_function::create_extism_function`), so nothing is shared with another thread
+unsafe impl Send for Container {}
+
 impl Container {
     /// fn_name may have module prefixes (e.g. `foo::exported_fn`)
     /// The '::' is converted to '_'
@@ -665,40 +1542,1596 @@ impl Container {
         fn_name: impl AsRef<str>,
         input: T,
     ) -> Result<U> {
+        return self.call_with(fn_name, input, CallOptions::default());
+    }
+
+    /// Like [`Self::call`], but with its own fuel limit instead of the one from [`UntrustedRustProject::with_fuel_limit`] (see [`Self::call_with`])
+    pub fn call_with_fuel_limit<'a, 'b, T: ToBytes<'a>, U: FromBytes<'b>>(
+        &'b mut self,
+        fn_name: impl AsRef<str>,
+        input: T,
+        fuel_limit: u64,
+    ) -> Result<U> {
+        return self.call_with(fn_name, input, CallOptions::default().with_fuel(fuel_limit));
+    }
+
+    /// The fuel used by the last call (including a failed one), or `None` if it had no fuel limit.
+    /// The first call after creating the container (or changing its fuel limit) also pays for initializing the untrusted code
+    pub fn last_call_fuel(&self) -> Option<u64> {
+        self.last_call_fuel
+    }
+
+    /// The limits that calls use unless they override them (see [`Self::call_with`]).
+    /// These start out as the limits of the [`UntrustedRustProject`]
+    pub fn default_call_options(&self) -> CallOptions {
+        self.default_call_options
+    }
+
+    /// Changes the limits for the later calls, without recreating the container. A limit that is `None` is removed
+    pub fn set_default_call_options(&mut self, call_options: CallOptions) {
+        self.default_call_options = call_options;
+    }
+
+    /// Like [`Self::call`], but with its own limits instead of the container's defaults (see [`Self::default_call_options`]).
+    /// Changing the fuel limit re-instantiates the untrusted code, which resets its state (e.g. `static`s).
+    /// The untrusted code is only compiled the first time that a fuel limit is used, so switching between a few of them stays cheap.
+    /// The memory limit is enforced by the host as the memory grows, so it can change without either
+    pub fn call_with<'a, 'b, T: ToBytes<'a>, U: FromBytes<'b>>(
+        &'b mut self,
+        fn_name: impl AsRef<str>,
+        input: T,
+        call_options: CallOptions,
+    ) -> Result<U> {
+        let call_options = call_options.or(&self.default_call_options);
+
         let exported_fn_name = if fn_name.as_ref().contains("::") {
             fn_name.as_ref().replace("::", "__")
         } else {
             format!("__{}", fn_name.as_ref())
         };
 
-        return match self.plugin.call(&exported_fn_name, input) {
-            Ok(val) => Ok(val),
-            Err(extism_err) => Err(match extism_err.to_string().as_str() {
-                "oom" => UntRustedError::RuntimeExceededMemory(fn_name.as_ref().to_string()),
-                "timeout" => UntRustedError::RuntimeExceededTimeout(fn_name.as_ref().to_string()),
-                _ => UntRustedError::Extism(extism_err),
-            })
-        };
-    }
+        // an earlier call could not re-instantiate the untrusted code after leaving it broken
+        if self.needs_reinstantiate {
+            self.reinstantiate()?;
+        }
 
-}
+        let plugin_limits = PluginLimits::from(call_options);
+        if plugin_limits != self.plugin_limits {
+            self.plugin = Plugin::new_from_compiled(self.compiled_plugin(plugin_limits)?)?;
+            self.plugin_limits = plugin_limits;
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // copy the output out of the plugin, so that the fuel can be read before it is decoded.
+        // Unlike `Plugin::call`, this does not fail on a non-zero return code, but the generated exports always set an error along with it
+        self.memory_limits.set_max_total_bytes(call_options.max_memory_bytes.map(|num_bytes| num_bytes.div_ceil(WASM_PAGE_SIZE) * WASM_PAGE_SIZE));
 
-    #[test]
-    fn test_basic() {
-        let rust_code = "pub fn add2(a: i32) -> i32 {\nreturn a + 2;\n}";
+        let plugin = &mut self.plugin;
+        let report_slot = GuestReportSlot::default();
+        let (output, interruption) = self.watchdog.run(plugin.cancel_handle(), call_options.timeout, || {
+            plugin.call_with_host_context::<T, &[u8], _>(&exported_fn_name, input, report_slot.clone()).map(|output| match call_options.max_output_bytes {
+                Some(max_output_bytes) if output.len() > max_output_bytes => Err(output.len()),
+                _ => Ok(output.to_vec()),
+            })
+        });
+        self.last_call_fuel = self.plugin.fuel_consumed();
 
-        let project = UntrustedRustProject::new(rust_code);
+        let report = report_slot.lock().unwrap_or_else(|err| err.into_inner()).take();
+        let output = output.map_err(|extism_err| self.classify_error(fn_name.as_ref(), extism_err, interruption, report));
 
-        let compiled_project = project.compile().unwrap();
+        // the untrusted code may have been stopped half way through changing its state, or have left its stack pointer broken,
+        // so it starts over from a fresh instance
+        if let Err(err) = &output {
+            if interruption.is_some() || Self::leaves_instance_broken(err) {
+                self.needs_reinstantiate = true;
+                self.reinstantiate()?;
+            }
+        }
 
-        let mut container = compiled_project.create_container().unwrap();
+        let output = output?;
+        self.output = output.map_err(|size| UntRustedError::RuntimeExceededOutputSize {
+            fn_name: fn_name.as_ref().to_string(),
+            size,
+            allowed: call_options.max_output_bytes.unwrap_or_default(),
+        })?;
+
+        let container: &'b Self = self;
+        return U::from_bytes(&container.output).map_err(UntRustedError::Extism);
+    }
+
+    /// Returns a handle that cancels the running call from another thread, which then fails with [`UntRustedError::Cancelled`].
+    /// The container stays usable: the next call starts from a fresh instance of the untrusted code, so its state (e.g. `static`s) is reset
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        return self.watchdog.cancel_handle();
+    }
+
+    /// Starts over from a fresh instance of the untrusted code, which resets its state
+    pub(crate) fn reinstantiate(&mut self) -> Result<()> {
+        self.plugin = Plugin::new_from_compiled(self.compiled_plugin(self.plugin_limits)?)?;
+        self.needs_reinstantiate = false;
+        return Ok(());
+    }
+
+    /// True if a call left the untrusted code broken, and it could not be re-instantiated yet
+    pub(crate) fn needs_reinstantiate(&self) -> bool {
+        self.needs_reinstantiate
+    }
+
+    /// The untrusted code compiled for the limits, which is only compiled the first time that the limits are used
+    fn compiled_plugin(&mut self, plugin_limits: PluginLimits) -> Result<&CompiledPlugin> {
+        if !self.compiled_plugins.contains_key(&plugin_limits) {
+            // each one holds the machine code of the whole project, so a caller that picks new limits for every call does not keep them all
+            if self.compiled_plugins.len() >= MAX_COMPILED_PLUGINS_PER_CONTAINER {
+                self.compiled_plugins.clear();
+            }
+            self.compiled_plugins.insert(plugin_limits, self.compiled.compile_plugin(plugin_limits, &self.memory_limits)?);
+        }
+
+        return Ok(&self.compiled_plugins[&plugin_limits]);
+    }
+
+    /// True for failures that stop the untrusted code in the middle of a call, i.e. every trap. A guest error is a normal return
+    fn leaves_instance_broken(err: &UntRustedError) -> bool {
+        return matches!(err, UntRustedError::GuestPanic { .. } | UntRustedError::GuestTrap { .. } | UntRustedError::FuelExhausted { .. }
+            | UntRustedError::RuntimeExceededHeapLimit { .. } | UntRustedError::RuntimeExceededMemory(_));
+    }
+
+    /// Stops the cancel handles that were handed out so far from cancelling later calls
+    pub(crate) fn revoke_cancel_handles(&mut self) {
+        self.watchdog = Watchdog::default();
+    }
+
+    /// Turns a failed call into the most specific error. Reports from the guest (errors, panics and the heap limiter) come first,
+    /// since the guest fails right after sending them. `report` is what the generated guest code reported for the call (see [`GuestReportSlot`])
+    fn classify_error(&self, fn_name: &str, extism_err: extism::Error, interruption: Option<Interruption>, report: Option<String>) -> UntRustedError {
+        let source_map = &self.compiled.source_map;
+
+        if let Some(payload) = report.as_deref().and_then(|report| report.strip_prefix(GUEST_ERROR_MSG)) {
+            return UntRustedError::GuestError {
+                function: fn_name.to_string(),
+                payload: serde_json::from_str(payload).unwrap_or_else(|_| serde_json::Value::String(payload.to_string())),
+            };
+        }
+
+        if let Some(panic_report) = report.as_deref().and_then(|report| report.strip_prefix(GUEST_PANIC_MSG)) {
+            let (message, location) = match serde_json::from_str::<(String, usize, usize)>(panic_report) {
+                // columns start at 1 in the panic location
+                Ok((message, line, column)) => (message, source_map.map((line, column.saturating_sub(1)), (line, column.saturating_sub(1)))),
+                Err(_) => (panic_report.to_string(), None),
+            };
+
+            return UntRustedError::GuestPanic {
+                function: fn_name.to_string(),
+                message,
+                location,
+            };
+        }
+
+        if let Some((requested, allowed)) = report.as_deref().and_then(Self::parse_heap_limit_exceeded) {
+            return UntRustedError::RuntimeExceededHeapLimit {
+                fn_name: fn_name.to_string(),
+                requested,
+                allowed,
+            };
+        }
+
+        // the untrusted code usually traps once its allocator fails, but it may as well fail in other ways
+        if self.memory_limits.exceeded() {
+            return UntRustedError::RuntimeExceededMemory(fn_name.to_string());
+        }
+
+        // only the watchdog interrupts calls
+        match interruption {
+            Some(Interruption::Cancelled) => return UntRustedError::Cancelled(fn_name.to_string()),
+            Some(Interruption::TimedOut) => return UntRustedError::RuntimeExceededTimeout(fn_name.to_string()),
+            None => (),
+        }
+
+        let trap = extism_err.chain().find_map(|err| err.downcast_ref::<wasmtime::Trap>()).copied();
+
+        // extism replaces the out of fuel trap with its own error, so the fuel that is left tells too
+        let used_all_fuel = matches!((self.plugin_limits.fuel, self.last_call_fuel), (Some(fuel), Some(consumed)) if consumed >= fuel);
+        if trap == Some(wasmtime::Trap::OutOfFuel) || used_all_fuel {
+            return UntRustedError::FuelExhausted {
+                function: fn_name.to_string(),
+                consumed: self.last_call_fuel.unwrap_or_default(),
+            };
+        }
+
+        if let Some(trap) = trap {
+            return UntRustedError::GuestTrap {
+                function: fn_name.to_string(),
+                trap: TrapKind::from(trap),
+            };
+        }
+
+        return UntRustedError::Extism(extism_err);
+    }
+
+    /// Looks up an exported function and checks that its param and return types match `I` and `O`, so that calling it cannot fail
+    /// because of a typo or a type mismatch. `I` is `()` for no params, the param itself, or a tuple of the params if there are more than one.
+    /// Both have to implement [`GuestTypeName`], which every exported host type does
+    pub fn typed_func<I: Serialize + GuestTypeName, O: DeserializeOwned + GuestTypeName>(&mut self, fn_name: impl AsRef<str>) -> Result<TypedFunc<'_, I, O>> {
+        let Some(export) = self.compiled.exports.iter().find(|export| export.name == fn_name.as_ref()).cloned() else {
+            return Err(UntRustedError::MissingExport(fn_name.as_ref().to_string()));
+        };
+
+        return TypedFunc::new(self, export);
+    }
+
+    /// Wraps the container in the proxy for a `#[exported_host_trait]`, so that the untrusted code can be used like any other implementation
+    /// of the trait. Fails if the compiled project does not export all of the trait's methods
+    pub fn into_proxy<T: ExportedHostTrait + ?Sized>(self) -> Result<T::Proxy> {
+        for method_name in T::method_names() {
+            if !self.plugin.function_exists(format!("{}__{}", T::trait_name(), method_name)) {
+                return Err(UntRustedError::MissingTraitMethod {
+                    trait_name: T::trait_name().to_string(),
+                    method_name: method_name.to_string(),
+                });
+            }
+        }
+
+        return Ok(T::create_proxy(self));
+    }
+
+    /// Parses the report of the guest heap limiter into `(requested, allowed)` bytes
+    fn parse_heap_limit_exceeded(msg: &str) -> Option<(usize, usize)> {
+        let mut requested = None;
+        let mut allowed = None;
+        for field in msg.strip_prefix(HEAP_LIMIT_EXCEEDED_MSG)?.split_whitespace() {
+            match field.split_once('=') {
+                Some(("requested", num_bytes)) => requested = num_bytes.parse().ok(),
+                Some(("allowed", num_bytes)) => allowed = num_bytes.parse().ok(),
+                _ => (),
+            }
+        }
+
+        return Some((requested?, allowed?));
+    }
+
+    /// Calls an exported function that returns `Result<T, E>`, where `E` is an exported host type or a `String`.
+    /// An `Err` from the untrusted code is returned as `Ok(Err(E))`, so that it cannot be mistaken for a failure of the container
+    pub fn call_result<'a, 'b, I: ToBytes<'a>, T: FromBytes<'b>, E: DeserializeOwned>(
+        &'b mut self,
+        fn_name: impl AsRef<str>,
+        input: I,
+    ) -> Result<std::result::Result<T, E>> {
+        return match self.call(fn_name, input) {
+            Ok(output) => Ok(Ok(output)),
+            Err(UntRustedError::GuestError { function, payload }) => serde_json::from_value(payload)
+                .map(Err)
+                .map_err(|err| UntRustedError::SerdeJson(function, err)),
+            Err(err) => Err(err),
+        };
+    }
+
+    /// Calls an exported function that takes more than one parameter.
+    /// The params are packed into a single json tuple on the guest side, so pass them as a tuple (e.g. `(10, 2)`)
+    pub fn call_args<'b, A: Serialize, U: FromBytes<'b>>(
+        &'b mut self,
+        fn_name: impl AsRef<str>,
+        args: A,
+    ) -> Result<U> {
+        return self.call(fn_name, Json(args));
+    }
+
+    /// Calls an exported function that does not return anything
+    pub fn call_unit<'a, T: ToBytes<'a>>(
+        &mut self,
+        fn_name: impl AsRef<str>,
+        input: T,
+    ) -> Result<()> {
+        return self.call(fn_name, input);
+    }
+
+    /// Calls an exported function that does not take any params
+    pub fn call_noarg<'b, U: FromBytes<'b>>(
+        &'b mut self,
+        fn_name: impl AsRef<str>,
+    ) -> Result<U> {
+        return self.call(fn_name, ());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unt_rust_ed_derive::{exported_host_trait, exported_host_type, host_function};
+
+    #[test]
+    fn test_basic() {
+        let rust_code = "pub fn add2(a: i32) -> i32 {\nreturn a + 2;\n}";
+
+        let project = UntrustedRustProject::new(rust_code);
+
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
 
         let outputs: i32 = container.call("add2", 10).unwrap();
 
         assert_eq!(12, outputs);
     }
+
+    #[test]
+    fn test_multiple_params() {
+        let rust_code = "pub fn add(a: i32, b: i32) -> i32 {\nreturn a + b;\n}\npub fn join(a: String, b: String, sep: char) -> String {\nformat!(\"{}{}{}\", a, sep, b)\n}";
+
+        let project = UntrustedRustProject::new(rust_code);
+
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
+
+        let outputs: i32 = container.call_args("add", (10, 2)).unwrap();
+        assert_eq!(12, outputs);
+
+        let outputs: String = container.call_args("join", ("a", "b", '-')).unwrap();
+        assert_eq!("a-b", outputs);
+    }
+
+    #[test]
+    fn test_private_items() {
+        let rust_code = "const OFFSET: i32 = 2;
+        struct Adder { offset: i32 }
+        impl Adder {
+            fn add(&self, a: i32) -> i32 { a + self.offset }
+        }
+        fn helper(a: i32) -> i32 { Adder { offset: OFFSET }.add(a) }
+        pub fn add2(a: i32) -> i32 { helper(a) }";
+
+        let project = UntrustedRustProject::new(rust_code);
+
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
+
+        let outputs: i32 = container.call("add2", 10).unwrap();
+
+        assert_eq!(12, outputs);
+    }
+
+    #[test]
+    fn test_tagged_export_policy() {
+        let rust_code = "pub fn add1(a: i32) -> i32 { a + 1 }
+        #[export]
+        pub fn add2(a: i32) -> i32 { a + 2 }
+        mod foo {
+            #[export]
+            fn add3(a: i32) -> i32 { a + 3 }
+        }";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_export_policy(ExportPolicy::Tagged);
+
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
+
+        let outputs: i32 = container.call("add2", 10).unwrap();
+        assert_eq!(12, outputs);
+
+        let outputs: i32 = container.call("foo::add3", 10).unwrap();
+        assert_eq!(13, outputs);
+
+        assert!(container.call::<i32, i32>("add1", 10).is_err());
+    }
+
+    #[test]
+    fn test_unit_and_noarg() {
+        let rust_code = "static mut COUNTER: i32 = 0;
+        pub fn reset() {
+            unsafe { COUNTER = 0; }
+        }
+        pub fn incr(a: i32) {
+            unsafe { COUNTER += a; }
+        }
+        pub fn get() -> i32 {
+            unsafe { COUNTER }
+        }";
+
+        let project = UntrustedRustProject::new(rust_code);
+
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
+
+        container.call_unit("incr", 5).unwrap();
+        container.call_unit("incr", 7).unwrap();
+
+        let outputs: i32 = container.call_noarg("get").unwrap();
+        assert_eq!(12, outputs);
+
+        container.call_unit("reset", ()).unwrap();
+
+        let outputs: i32 = container.call_noarg("get").unwrap();
+        assert_eq!(0, outputs);
+    }
+
+    #[test]
+    fn test_max_memory_bytes_uses_wasm_pages() {
+        let project = UntrustedRustProject::new("")
+            .with_max_memory_bytes(1024 * 1024);
+        assert_eq!(Some(16), project.runtime_memory_options.max_pages);
+
+        let project = UntrustedRustProject::new("")
+            .with_max_memory_bytes(1024 * 1024 + 1);
+        assert_eq!(Some(17), project.runtime_memory_options.max_pages);
+    }
+
+    #[test]
+    fn test_cache_key_covers_dependencies() {
+        let rust_code = "pub fn add2(a: i32) -> i32 {\nreturn a + 2;\n}";
+
+        let cache_key = UntrustedRustProject::new(rust_code).calculate_cache_key().unwrap();
+
+        let cache_key_with_dep = UntrustedRustProject::new(rust_code)
+            .with_dependency("rand = \"0.8\"")
+            .calculate_cache_key().unwrap();
+
+        assert_ne!(cache_key.hash(), cache_key_with_dep.hash());
+        assert_eq!(vec!["dependencies", "cargo_toml_hash"], cache_key.diff(&cache_key_with_dep));
+    }
+
+    #[test]
+    fn test_compile_cache() {
+        let cache_dir = TempDir::new().unwrap();
+        let compile_cache = CompileCache::new(cache_dir.path()).unwrap();
+
+        let rust_code = "pub fn add2(a: i32) -> i32 {\nreturn a + 2;\n}";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_compile_cache(compile_cache.clone());
+
+        project.compile().unwrap();
+
+        // the second compile should come from the cache
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
+        let outputs: i32 = container.call("add2", 10).unwrap();
+        assert_eq!(12, outputs);
+
+        let stats = compile_cache.stats().unwrap();
+        assert_eq!(1, stats.num_entries);
+        assert_eq!(1, stats.hits);
+        assert_eq!(1, stats.misses);
+
+        compile_cache.purge().unwrap();
+        assert_eq!(0, compile_cache.stats().unwrap().num_entries);
+    }
+
+    #[test]
+    fn test_compile_cache_evicts_least_recently_used() {
+        let cache_dir = TempDir::new().unwrap();
+        let compile_cache = CompileCache::new(cache_dir.path()).unwrap();
+
+        let now = std::time::SystemTime::now();
+        let mut size_bytes = 0;
+        let mut project_hashes = Vec::new();
+        for (idx, rust_code) in ["pub fn a() {}", "pub fn b() {}", "pub fn c() {}"].iter().enumerate() {
+            let cache_key = UntrustedRustProject::new(rust_code).calculate_cache_key().unwrap();
+            let compiled_project = CompiledUntrustedRustProject {
+                project_hash: cache_key.hash(),
+                cache_key,
+                manifest: Manifest::new(vec![Wasm::data(vec![0; 1024])]),
+                fuel_limit: None,
+                target: WasmCompileTarget::default(),
+                diagnostics: Vec::new(),
+                source_map: SourceMap::default(),
+                exports: Vec::new(),
+                host_functions: Vec::new(),
+                native_code_dir: None,
+            };
+            compile_cache.save(&compiled_project).unwrap();
+
+            // pretend that each entry was used a minute after the previous one
+            let entry_path = cache_dir.path().join(format!("{}.{}", compiled_project.project_hash, CACHE_FILE_EXTENSION));
+            let file = File::options().write(true).open(&entry_path).unwrap();
+            file.set_modified(now + Duration::from_secs(60 * idx as u64)).unwrap();
+            size_bytes = file.metadata().unwrap().len();
+
+            project_hashes.push(compiled_project.project_hash);
+        }
+
+        // left behind by a writer that crashed
+        let orphaned_temp_file = cache_dir.path().join(".unt-rust-ed-tmpXYZ123");
+        fs::write(&orphaned_temp_file, [0; 16]).unwrap();
+
+        // room for two entries
+        let compile_cache = compile_cache.with_max_size_bytes(size_bytes * 2);
+        assert_eq!(1, compile_cache.evict().unwrap());
+        assert_eq!(1, compile_cache.stats().unwrap().evictions);
+        assert!(!orphaned_temp_file.exists());
+
+        let mut remaining: Vec<String> = fs::read_dir(cache_dir.path()).unwrap()
+            .map(|dir_entry| dir_entry.unwrap().path())
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some(CACHE_FILE_EXTENSION))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().to_string())
+            .collect();
+        remaining.sort();
+
+        let mut expected = vec![project_hashes[1].clone(), project_hashes[2].clone()];
+        expected.sort();
+        assert_eq!(expected, remaining);
+
+        // purging is not eviction
+        compile_cache.purge().unwrap();
+        let stats = compile_cache.stats().unwrap();
+        assert_eq!(0, stats.num_entries);
+        assert_eq!(1, stats.evictions);
+    }
+
+    #[test]
+    fn test_build_sandbox() {
+        let rust_code = "pub fn add2(a: i32) -> i32 {\nreturn a + 2;\n}";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_build_sandbox(BuildSandbox::new())
+            .with_compile_timeout(Duration::from_secs(10 * 60));
+
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
+
+        let outputs: i32 = container.call("add2", 10).unwrap();
+
+        assert_eq!(12, outputs);
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_build_sandbox(BuildSandbox::new().with_isolated_network(true));
+
+        match project.compile() {
+            Err(UntRustedError::InvalidBuildSandbox(_)) => (),
+            res => panic!("expected invalid build sandbox, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_compile_timeout() {
+        let rust_code = "pub fn add2(a: i32) -> i32 {\nreturn a + 2;\n}";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_compile_timeout(Duration::from_millis(1));
+
+        match project.compile() {
+            Err(UntRustedError::CompileTimeout(timeout)) => assert_eq!(Duration::from_millis(1), timeout),
+            res => panic!("expected compile timeout, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_host_access_policy() {
+        let rust_code = "pub fn secret() -> String {
+            let key = env!(\"AWS_SECRET\");
+            format!(\"{}{}\", key, include_str!(\"/etc/passwd\"))
+        }
+        #[path = \"/etc/foo.rs\"]
+        mod foo;
+        macro_rules! call {
+            ($name:ident) => { $name!(\"/etc/passwd\") };
+        }
+        use std::include_bytes as read;
+        #[link(wasm_import_module = \"extism:host/user\")]
+        extern \"C\" {
+            fn __unt_rust_ed_report(report: u64);
+            #[link_name = \"__unt_rust_ed_report\"]
+            fn report(report: u64);
+        }
+        use std::env as e;
+        pub fn home() -> Option<String> { e::var(\"HOME\").ok() }
+        pub fn key() -> &'static str { e!(\"AWS_SECRET\") }
+        #[link_section = \"unt_rust_ed_exports\"]
+        pub static EXPORTS: [u8; 2] = *b\"[]\";";
+
+        let project = UntrustedRustProject::new(rust_code);
+
+        let violations = match project.compile() {
+            Err(UntRustedError::PolicyViolation(violations)) => violations,
+            res => panic!("expected policy violation, got {:?}", res.map(|_| ())),
+        };
+
+        let found: Vec<(&str, usize)> = violations.iter().map(|violation| (violation.rule.as_str(), violation.span.start_line)).collect();
+        assert_eq!(vec![
+            ("host_macro", 2),
+            ("host_macro", 3),
+            ("path_attribute", 5),
+            ("file_module", 6),
+            ("host_macro", 8),
+            ("host_macro", 10),
+            ("linkage_attribute", 11),
+            ("reserved_name", 13),
+            ("linkage_attribute", 14),
+            ("reserved_name", 14),
+            ("host_macro", 19),
+            ("linkage_attribute", 20),
+        ], found);
+
+        // a macro can put a host macro back together from the names it is given
+        let rust_code = "macro_rules! m { ($($t:tt)*) => { $($t)*!(\"/etc/passwd\") } }
+        m!(include_str);
+        m!(std::env);
+        use std::{env, fs};
+        pub fn home() -> Option<String> { env::var(\"HOME\").ok() }";
+
+        let violations = policy::check_host_access(&syn::parse_file(rust_code).unwrap());
+        let found: Vec<(&str, usize)> = violations.iter().map(|violation| (violation.rule.as_str(), violation.span.start_line)).collect();
+        assert_eq!(vec![
+            ("host_macro", 1),
+            ("host_macro", 2),
+            ("host_macro", 3),
+        ], found);
+    }
+
+    #[test]
+    fn test_source_policies() {
+        let rust_code = "use std::{fs, process::exit};
+        extern \"C\" {
+            fn host_fn();
+        }
+        pub fn a() -> i32 {
+            unsafe { host_fn(); }
+            std::process::abort();
+        }
+        pub fn b() -> i32 { ((((1)))) }";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_policy(PolicySet::new()
+                .with(policy::DenyUnsafe)
+                .with(policy::DenyExternBlocks)
+                .with(policy::DenyPaths::new(&["std::process"])))
+            .with_policy(policy::MaxFunctions(2))
+            .with_policy(policy::MaxNestingDepth(4));
+
+        let violations = match project.compile() {
+            Err(UntRustedError::PolicyViolation(violations)) => violations,
+            res => panic!("expected policy violation, got {:?}", res.map(|_| ())),
+        };
+
+        let found: Vec<(&str, usize)> = violations.iter().map(|violation| (violation.rule.as_str(), violation.span.start_line)).collect();
+        assert_eq!(vec![
+            ("deny_unsafe", 6),
+            ("deny_extern_blocks", 2),
+            ("deny_paths", 1),
+            ("deny_paths", 7),
+            ("max_functions", 9),
+            ("max_nesting_depth", 9),
+        ], found);
+
+        let violations = policy::MaxAstNodes(5).check(&syn::parse_file(rust_code).unwrap());
+        assert_eq!(1, violations.len());
+
+        // renaming or glob importing a parent would hide the denied path
+        let rust_code = "use std as s;
+        use std::{self as t, fs};
+        extern crate std as u;
+        use std::*;
+        use std::{io, *};
+        use std::env as e;
+        pub fn c() { s::process::exit(0) }";
+
+        let violations = policy::DenyPaths::new(&["std::process"]).check(&syn::parse_file(rust_code).unwrap());
+        let found: Vec<usize> = violations.iter().map(|violation| violation.span.start_line).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5], found);
+    }
+
+    struct HostPoint;
+
+    impl ExportedHostType for HostPoint {
+        fn typename() -> &'static str {
+            "HostPoint"
+        }
+
+        fn typedef_as_string() -> &'static str {
+            "pub struct HostPoint { pub x: i32, pub y: i32 }"
+        }
+    }
+
+    #[test]
+    fn test_compile_error_diagnostics() {
+        // the generated lib.rs is reformatted and has extra items, so its line numbers differ from these
+        let rust_code = "pub fn add(a: i32,
+            b: i32) -> i32 {
+
+
+            let unused = 1;
+            a + b + {}
+        }";
+
+        let project = UntrustedRustProject::new(&rust_code.replace("{}", "\"x\""))
+            .with_exported_host_type::<HostPoint>();
+
+        let diagnostics = match project.compile() {
+            Err(UntRustedError::CompileError(diagnostics)) => diagnostics,
+            res => panic!("expected compile error, got {:?}", res.map(|_| ())),
+        };
+
+        let error = diagnostics.iter().find(|diagnostic| diagnostic.level == DiagnosticLevel::Error).unwrap();
+        assert_eq!(Some("E0277"), error.code.as_deref());
+        assert_eq!(6, error.primary_span().unwrap().start_line);
+
+        // warnings come with a successful build, and none of them are about generated code
+        let compiled_project = UntrustedRustProject::new(&rust_code.replace("{}", "1"))
+            .with_exported_host_type::<HostPoint>()
+            .compile()
+            .unwrap();
+
+        assert_eq!(1, compiled_project.diagnostics().len(), "{:?}", compiled_project.diagnostics());
+        let warning = &compiled_project.diagnostics()[0];
+        assert_eq!(DiagnosticLevel::Warning, warning.level);
+        assert!(warning.message.contains("unused"));
+        assert_eq!(5, warning.primary_span().unwrap().start_line);
+    }
+
+    #[test]
+    fn test_check() {
+        let check_dir = TempDir::new().unwrap();
+
+        let diagnostics = UntrustedRustProject::new("pub fn add(a: i32, b: i32) -> i32 {\n    let unused = 1;\n    a + b\n}")
+            .with_check_dir(check_dir.path())
+            .check()
+            .unwrap();
+        assert_eq!(1, diagnostics.len(), "{:?}", diagnostics);
+        assert_eq!(2, diagnostics[0].primary_span().unwrap().start_line);
+
+        // the second check reuses the warm workspace
+        let res = UntrustedRustProject::new("pub fn add(a: i32, b: i32) -> i32 {\n    a + \"b\"\n}")
+            .with_check_dir(check_dir.path())
+            .check();
+        match res {
+            Err(UntRustedError::CompileError(diagnostics)) => {
+                assert_eq!(Some("E0277"), diagnostics[0].code.as_deref());
+                assert_eq!(2, diagnostics[0].primary_span().unwrap().start_line);
+            },
+            res => panic!("expected compile error, got {:?}", res),
+        }
+        assert_eq!(1, fs::read_dir(check_dir.path()).unwrap().count());
+
+        // without a check dir, the check runs in a fresh temp directory
+        let diagnostics = UntrustedRustProject::new("pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}")
+            .check()
+            .unwrap();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[exported_host_type]
+    pub struct Price {
+        pub cents: u64,
+    }
+
+    #[host_function]
+    fn lookup_price(sku: String) -> Price {
+        Price {
+            cents: if sku == "apple" { 50 } else { 200 },
+        }
+    }
+
+    #[host_function]
+    fn log_total(total: u64) {
+        assert_eq!(150, total);
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let rust_code = "pub fn total(sku: String, quantity: u64) -> u64 {
+            let total = lookup_price(sku).cents * quantity;
+            log_total(total);
+            total
+        }";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Price>()
+            .with_host_function::<lookup_price>()
+            .with_host_function::<log_total>();
+
+        let mut container = project.compile().unwrap().create_container().unwrap();
+
+        let total: u64 = container.call_args("total", ("apple", 3)).unwrap();
+        assert_eq!(150, total);
+
+        // the stubs are not exported
+        assert!(container.call::<&str, String>("lookup_price", "apple").is_err());
+
+        // a host function that panics fails the call, instead of unwinding through the runtime
+        assert!(container.call_args::<_, u64>("total", ("pear", 3)).is_err());
+    }
+
+    #[exported_host_type]
+    pub struct Order {
+        pub quantity: u64,
+    }
+
+    #[exported_host_trait]
+    pub trait Pricing {
+        fn quote(&self, order: Order, coupon: Option<String>) -> Price;
+        fn currency(&self) -> String {
+            "USD".to_string()
+        }
+    }
+
+    #[test]
+    fn test_exported_host_trait() {
+        let rust_code = "#[derive(Default)]
+        pub struct Discounted;
+
+        impl Pricing for Discounted {
+            fn quote(&self, order: Order, coupon: Option<String>) -> Price {
+                assert!(order.quantity > 0, \"empty order\");
+                let cents = order.quantity * 100;
+                Price { cents: if coupon.is_some() { cents / 2 } else { cents } }
+            }
+        }";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Order>()
+            .with_exported_host_type::<Price>()
+            .with_exported_host_trait::<dyn Pricing>();
+
+        let container = project.compile().unwrap().create_container().unwrap();
+        let proxy = container.into_proxy::<dyn Pricing>().unwrap();
+
+        // a failed call is returned by the try_ methods, instead of panicking
+        assert_eq!(200, proxy.try_quote(Order { quantity: 2 }, None).unwrap().cents);
+        assert!(matches!(proxy.try_quote(Order { quantity: 0 }, None), Err(UntRustedError::GuestPanic { .. })));
+
+        let pricing: Box<dyn Pricing> = Box::new(proxy);
+        assert_eq!(300, pricing.quote(Order { quantity: 3 }, None).cents);
+        assert_eq!(150, pricing.quote(Order { quantity: 3 }, Some("HALF".to_string())).cents);
+        assert_eq!("USD", pricing.currency());
+
+        // a missing method is found before building
+        let res = UntrustedRustProject::new("#[derive(Default)]\npub struct Empty;\nimpl Pricing for Empty {}")
+            .with_exported_host_type::<Order>()
+            .with_exported_host_type::<Price>()
+            .with_exported_host_trait::<dyn Pricing>()
+            .compile();
+        match res {
+            Err(UntRustedError::InvalidTraitImpl { trait_name, problems }) => {
+                assert_eq!("Pricing", trait_name);
+                assert_eq!(vec!["missing method `quote`".to_string()], problems);
+            },
+            res => panic!("expected invalid trait impl, got {:?}", res.map(|_| ())),
+        }
+
+        // the types are compared through aliases
+        let rust_code = "#[derive(Default)]
+        pub struct Wrong;
+        type Cost = Price;
+        type Coupon = Option<String>;
+        impl Pricing for Wrong {
+            fn quote(&self, order: u64, coupon: Coupon) -> Cost { Price { cents: order } }
+            fn currency(&self) -> u32 { 1 }
+        }";
+        let res = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Order>()
+            .with_exported_host_type::<Price>()
+            .with_exported_host_trait::<dyn Pricing>()
+            .compile();
+        match res {
+            Err(UntRustedError::InvalidTraitImpl { problems, .. }) => {
+                assert_eq!(vec![
+                    "`quote` at 6:15 takes `u64` as param 1, but the trait has `Order`".to_string(),
+                    "`currency` at 7:15 returns `u32`, but the trait returns `String`".to_string(),
+                ], problems);
+            },
+            res => panic!("expected invalid trait impl, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_typed_func() {
+        let rust_code = "pub fn add2(a: i32) -> i32 { a + 2 }
+        pub fn greet(name: String) -> String { format!(\"hi {}\", name) }
+        pub fn origin() -> Price { Price { cents: 0 } }
+        pub mod math {
+            pub fn sum(values: Vec<i64>, offset: i64) -> i64 { values.iter().sum::<i64>() + offset }
+        }";
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Price>()
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        let mut add2 = container.typed_func::<i32, i32>("add2").unwrap();
+        assert_eq!(12, add2.call(10).unwrap());
+        assert_eq!(3, add2.call(1).unwrap());
+
+        assert_eq!("hi bob", container.typed_func::<String, String>("greet").unwrap().call("bob".to_string()).unwrap());
+        assert_eq!(0, container.typed_func::<(), Price>("origin").unwrap().call(()).unwrap().cents);
+        assert_eq!(7, container.typed_func::<(Vec<i64>, i64), i64>("math::sum").unwrap().call((vec![1, 2], 4)).unwrap());
+
+        assert!(matches!(container.typed_func::<i32, i32>("add3"), Err(UntRustedError::MissingExport(_))));
+        match container.typed_func::<i64, i32>("add2") {
+            Err(UntRustedError::TypedFuncMismatch { fn_name, host_type, guest_type }) => {
+                assert_eq!("add2", fn_name);
+                assert_eq!("i64", host_type);
+                assert_eq!("i32", guest_type);
+            },
+            res => panic!("expected type mismatch, got {:?}", res.map(|_| ())),
+        }
+        match container.typed_func::<(Vec<u64>, i64), Price>("math::sum") {
+            Err(UntRustedError::TypedFuncMismatch { host_type, guest_type, .. }) => {
+                assert_eq!("(Vec<u64>, i64)", host_type);
+                assert_eq!("(Vec<i64>, i64)", guest_type);
+            },
+            res => panic!("expected type mismatch, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_export_metadata() {
+        let rust_code = "/// Gets the price of
+        /// an order
+        pub fn quote(order: Order) -> Price { Price { cents: order.quantity * 3 } }
+        pub mod math {
+            pub fn add(a: i32, b: i32) -> i32 { a + b }
+        }";
+
+        let compiled = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Order>()
+            .with_exported_host_type::<Price>()
+            .compile()
+            .unwrap();
+
+        let exports = compiled.exports();
+        assert_eq!(2, exports.len());
+
+        let quote = exports.iter().find(|export| export.name == "quote").unwrap();
+        assert_eq!("__quote", quote.mangled_name);
+        assert_eq!(vec!["Order".to_string()], quote.params);
+        assert_eq!(Some("Price".to_string()), quote.return_type);
+        assert_eq!(vec!["Order".to_string(), "Price".to_string()], quote.host_types);
+        assert_eq!(vec!["Gets the price of".to_string(), "an order".to_string()], quote.docs);
+        assert_eq!(Encoding::Json, quote.input_encoding);
+
+        let add = exports.iter().find(|export| export.name == "math::add").unwrap();
+        assert_eq!("math__add", add.mangled_name);
+        assert_eq!(vec!["i32".to_string(), "i32".to_string()], add.params);
+        assert!(add.host_types.is_empty());
+        assert!(add.docs.is_empty());
+        assert_eq!(Encoding::JsonTuple, add.input_encoding);
+    }
+
+    #[test]
+    fn test_declared_exports() {
+        let rust_code = "pub fn process(order: Order) -> i64 { order.quantity as i64 }
+        pub mod setup {
+            pub fn init(_name: String) {}
+        }";
+
+        let res = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Order>()
+            .with_exported_host_type::<Price>()
+            .with_required_export("process", "fn(Order) -> Price")
+            .with_required_export("finish", "fn()")
+            .with_optional_export("setup::init", "fn(String, u32)")
+            .compile();
+
+        match res {
+            Err(UntRustedError::InvalidExports(problems)) => assert_eq!(vec![
+                "missing function finish".to_string(),
+                "process returns i64, expected Price".to_string(),
+                "setup::init takes 1 params, expected 2".to_string(),
+            ], problems),
+            res => panic!("expected invalid exports, got {:?}", res.map(|_| ())),
+        }
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Order>()
+            .with_required_export("process", "fn(Order) -> i64")
+            .with_optional_export("setup::init", "fn(String)")
+            .with_optional_export("finish", "fn()")
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        assert_eq!(4, container.typed_func::<Order, i64>("process").unwrap().call(Order { quantity: 4 }).unwrap());
+    }
+
+    #[test]
+    fn test_nested_and_borrowed_types() {
+        let rust_code = "use std::collections::HashMap;
+        pub fn score(orders: Vec<Order>) -> Option<Price> {
+            if orders.is_empty() { None } else { Some(Price { cents: orders.iter().map(|order| order.quantity).sum() }) }
+        }
+        pub fn largest(orders: &[Order], bonus: &HashMap<String, u64>) -> (Order, u64) {
+            let order = orders.iter().max_by_key(|order| order.quantity).unwrap();
+            (Order { quantity: order.quantity }, bonus.values().sum())
+        }
+        pub fn shout(name: &str) -> String { name.to_uppercase() }
+        pub fn bump(counter: &mut u32) -> u32 { *counter += 1; *counter }
+        pub fn flip(on: bool) -> bool { !on }";
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Order>()
+            .with_exported_host_type::<Price>()
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        let mut score = container.typed_func::<Vec<Order>, Option<Price>>("score").unwrap();
+        assert_eq!(Some(5), score.call(vec![Order { quantity: 2 }, Order { quantity: 3 }]).unwrap().map(|price| price.cents));
+        assert!(score.call(Vec::new()).unwrap().is_none());
+
+        let bonus = HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]);
+        let (order, bonus_total) = container.typed_func::<(Vec<Order>, HashMap<String, u64>), (Order, u64)>("largest").unwrap()
+            .call((vec![Order { quantity: 2 }, Order { quantity: 7 }], bonus))
+            .unwrap();
+        assert_eq!(7, order.quantity);
+        assert_eq!(3, bonus_total);
+
+        assert_eq!("HI", container.typed_func::<String, String>("shout").unwrap().call("hi".to_string()).unwrap());
+        assert_eq!(5, container.typed_func::<u32, u32>("bump").unwrap().call(4).unwrap());
+        assert!(container.typed_func::<bool, bool>("flip").unwrap().call(false).unwrap());
+
+        for rust_code in ["pub fn greet(name: Option<&str>) -> String { name.unwrap_or(\"?\").to_string() }",
+                          "pub fn count(orders: (&Order, i32)) -> i32 { orders.1 }"] {
+            let res = UntrustedRustProject::new(rust_code).with_exported_host_type::<Order>().compile();
+            assert!(matches!(res, Err(UntRustedError::UnsupportedFnArg(_))), "expected unsupported param, got {:?}", res.map(|_| ()));
+        }
+    }
+
+    #[test]
+    fn test_path_resolution() {
+        let rust_code = "use my_sdk::Order as SdkOrder;
+        use my_sdk::{self as sdk, shapes::*};
+        type Alias = sdk::Price;
+        mod nested {
+            use super::*;
+            pub use other_sdk::Order;
+        }";
+
+        let ast: syn::File = syn::parse_str(rust_code).unwrap();
+        let known_types = HashSet::from(["my_sdk::Order".to_string(), "my_sdk::Price".to_string(), "my_sdk::shapes::Circle".to_string(),
+                                         "Legacy".to_string()]);
+        let path_resolver = PathResolver::new(&ast.items, known_types);
+
+        let resolve = |mod_path: &[&str], path: &str| {
+            let mod_path: Vec<String> = mod_path.iter().map(|mod_name| mod_name.to_string()).collect();
+            path_resolver.resolve(&mod_path, &syn::parse_str(path).unwrap())
+        };
+
+        assert_eq!("my_sdk::Order", resolve(&[], "SdkOrder"));
+        assert_eq!("my_sdk::Order", resolve(&[], "my_sdk :: Order"));
+        assert_eq!("my_sdk::Order", resolve(&[], "::my_sdk::Order"));
+        assert_eq!("my_sdk::Price", resolve(&[], "Alias"));
+        assert_eq!("my_sdk::Price", resolve(&[], "sdk::Price"));
+        assert_eq!("my_sdk::shapes::Circle", resolve(&[], "Circle"));
+        assert_eq!("crate::Square", resolve(&[], "Square"));
+        assert_eq!("my_sdk::Price", resolve(&["nested"], "Alias"));
+        assert_eq!("my_sdk::Order", resolve(&["nested"], "crate::SdkOrder"));
+        assert_eq!("other_sdk::Order", resolve(&[], "nested::Order"));
+        assert_eq!("crate::nested::Thing", resolve(&["nested"], "self::Thing"));
+
+        let is_known = |path: &str| path_resolver.is_known_type(&[], &syn::parse_str(path).unwrap());
+        assert!(is_known("SdkOrder"));
+        assert!(is_known("Circle"));
+        assert!(is_known("legacy_sdk::Legacy"));
+        assert!(!is_known("nested::Order"));
+        assert!(!is_known("Square"));
+
+        assert_eq!("my_sdk::Order", paths::normalize_path(" ::my_sdk :: Order"));
+
+        let sig: syn::Signature = syn::parse_str("fn f(orders: Vec<SdkOrder>, shape: Option<Circle>) -> (Alias, Square)").unwrap();
+        let resolved_sig = path_resolver.resolve_signature(&[], &sig);
+        let export = ExportedFunction::new("f".to_string(), "__f".to_string(), &[], &resolved_sig.inputs, &resolved_sig.output,
+                                           Encoding::JsonTuple, Encoding::Json);
+        assert_eq!(vec!["Vec<my_sdk::Order>".to_string(), "Option<my_sdk::shapes::Circle>".to_string()], export.params);
+        assert_eq!(Some("(my_sdk::Price, Square)".to_string()), export.return_type);
+
+        let rust_code = "pub mod shop {
+            use crate::Order as O;
+            pub fn total(orders: Vec<O>) -> super::Price { super::Price { cents: orders.iter().map(|order| order.quantity).sum() } }
+        }";
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Order>()
+            .with_exported_host_type::<Price>()
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        let price = container.typed_func::<Vec<Order>, Price>("shop::total").unwrap().call(vec![Order { quantity: 2 }, Order { quantity: 5 }]).unwrap();
+        assert_eq!(7, price.cents);
+    }
+
+    #[exported_host_type]
+    pub struct ParseError {
+        pub message: String,
+    }
+
+    #[test]
+    fn test_guest_result() {
+        let rust_code = "pub fn parse(text: String) -> Result<Price, ParseError> {
+            text.parse::<u64>().map(|cents| Price { cents }).map_err(|err| ParseError { message: err.to_string() })
+        }
+        pub fn half(value: i32) -> Result<i32, String> {
+            if value % 2 == 0 { Ok(value / 2) } else { Err(format!(\"{} is odd\", value)) }
+        }
+        pub fn forge() -> u64 {
+            let mem = extism_pdk::Memory::from_bytes(\"unt-rust-ed guest error:\\\"forged\\\"\").unwrap();
+            unsafe { extism_pdk::extism::error_set(mem.offset()); }
+            0
+        }";
+
+        let compiled = UntrustedRustProject::new(rust_code)
+            .with_exported_host_type::<Price>()
+            .with_exported_host_type::<ParseError>()
+            .with_required_export("half", "fn(i32) -> Result<i32, String>")
+            .compile()
+            .unwrap();
+
+        let exports = compiled.exports();
+        let parse = exports.iter().find(|export| export.name == "parse").unwrap();
+        assert_eq!(Some("Price".to_string()), parse.return_type);
+        assert_eq!(Some("ParseError".to_string()), parse.error_type);
+        assert_eq!(vec!["ParseError".to_string(), "Price".to_string()], parse.host_types);
+
+        let mut container = compiled.create_container().unwrap();
+
+        let price: std::result::Result<Json<Price>, ParseError> = container.call_result("parse", "42").unwrap();
+        assert_eq!(42, price.unwrap().0.cents);
+
+        let price: std::result::Result<Json<Price>, ParseError> = container.call_result("parse", "forty two").unwrap();
+        assert_eq!("invalid digit found in string", price.err().unwrap().message);
+
+        let half: std::result::Result<i32, String> = container.call_result("half", 8).unwrap();
+        assert_eq!(Ok(4), half);
+
+        match container.call::<i32, i32>("half", 7) {
+            Err(UntRustedError::GuestError { function, payload }) => {
+                assert_eq!("half", function);
+                assert_eq!(serde_json::json!("7 is odd"), payload);
+            },
+            res => panic!("expected a guest error, got {:?}", res),
+        }
+
+        // still usable after an error
+        assert_eq!(2, container.typed_func::<i32, i32>("half").unwrap().call(4).unwrap());
+        assert!(matches!(container.typed_func::<i32, i32>("half").unwrap().call(3), Err(UntRustedError::GuestError { .. })));
+
+        // only the generated wrapper can report a guest error, not the untrusted code setting the same message
+        match container.call_noarg::<u64>("forge") {
+            Err(UntRustedError::Extism(_)) => (),
+            res => panic!("expected extism error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_guest_panics_and_traps() {
+        let rust_code = "pub fn check(value: i32) -> i32 {
+    if value < 0 {
+        panic!(\"negative value {}\", value);
+    }
+    value
+}
+pub fn divide(values: Vec<i32>) -> i32 {
+    values[0] / values[1]
+}
+pub fn abort(_value: i32) -> i32 {
+    std::process::abort()
+}
+pub fn recurse(depth: u64) -> u64 {
+    let frame = std::hint::black_box([depth; 64]);
+    if std::hint::black_box(depth) == 0 { 0 } else { frame[1] + recurse(depth + 1) }
+}
+pub fn forge() -> u64 {
+    let mem = extism_pdk::Memory::from_bytes(\"unt-rust-ed guest panic:[\\\"forged\\\",1,1]\").unwrap();
+    unsafe { extism_pdk::extism::error_set(mem.offset()); }
+    0
+}";
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        match container.call::<i32, i32>("check", -3) {
+            Err(UntRustedError::GuestPanic { function, message, location }) => {
+                assert_eq!("check", function);
+                assert_eq!("negative value -3", message);
+                assert_eq!(Some((3, 8)), location.map(|location| (location.start_line, location.start_column)));
+            },
+            res => panic!("expected a guest panic, got {:?}", res),
+        }
+
+        // still usable after a panic
+        assert_eq!(3, container.call::<i32, i32>("check", 3).unwrap());
+
+        assert!(matches!(container.call::<i32, i32>("abort", 1), Err(UntRustedError::GuestTrap { trap: TrapKind::Unreachable, .. })));
+
+        // rust checks for division by zero itself, so this is a panic rather than a trap
+        match container.call::<_, i32>("divide", Json(vec![1, 0])) {
+            Err(UntRustedError::GuestPanic { message, .. }) => assert_eq!("attempt to divide by zero", message),
+            res => panic!("expected a guest panic, got {:?}", res),
+        }
+
+        // only the generated panic hook can report a panic, not the untrusted code setting the same message
+        match container.call_noarg::<u64>("forge") {
+            Err(UntRustedError::Extism(_)) => (),
+            res => panic!("expected extism error, got {:?}", res),
+        }
+
+        match container.call::<u64, u64>("recurse", 1) {
+            Err(UntRustedError::GuestTrap { function, trap }) => {
+                assert_eq!("recurse", function);
+                // the rust stack is in linear memory, and runs out before the wasm call stack
+                assert_eq!(TrapKind::MemoryOutOfBounds, trap);
+            },
+            res => panic!("expected a trap, got {:?}", res),
+        }
+
+        // running out of stack leaves the guest's stack pointer broken, so this only works on a fresh instance
+        assert_eq!(3, container.call::<i32, i32>("check", 3).unwrap());
+        match container.call::<i32, i32>("check", -1) {
+            Err(UntRustedError::GuestPanic { message, .. }) => assert_eq!("negative value -1", message),
+            res => panic!("expected a guest panic, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_fuel_limit() {
+        let rust_code = "pub fn spin(iterations: u64) -> u64 {
+    let mut total: u64 = 0;
+    for i in 0..iterations {
+        total = std::hint::black_box(total.wrapping_add(i));
+    }
+    total
+}";
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .with_fuel_limit(100_000)
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        // the first call also initializes the untrusted code
+        assert_eq!(45, container.call::<u64, u64>("spin", 10).unwrap());
+        let first_consumed = container.last_call_fuel().unwrap();
+        assert_eq!(45, container.call::<u64, u64>("spin", 10).unwrap());
+        let consumed = container.last_call_fuel().unwrap();
+        assert!(consumed > 0 && consumed < first_consumed);
+
+        // the same call always uses the same fuel
+        container.call::<u64, u64>("spin", 10).unwrap();
+        assert_eq!(Some(consumed), container.last_call_fuel());
+
+        match container.call::<u64, u64>("spin", 1_000_000) {
+            Err(UntRustedError::FuelExhausted { function, consumed }) => {
+                assert_eq!("spin", function);
+                assert_eq!(100_000, consumed);
+            },
+            res => panic!("expected running out of fuel, got {:?}", res),
+        }
+
+        assert_eq!(499_999_500_000, container.call_with_fuel_limit::<u64, u64>("spin", 1_000_000, 100_000_000).unwrap());
+        assert!(container.last_call_fuel().unwrap() > 100_000);
+
+        // back to the project's limit
+        assert!(matches!(container.call::<u64, u64>("spin", 1_000_000), Err(UntRustedError::FuelExhausted { .. })));
+
+        // switching between limits reuses the untrusted code compiled for each one
+        container.call_with_fuel_limit::<u64, u64>("spin", 10, 100_000_000).unwrap();
+        container.call::<u64, u64>("spin", 10).unwrap();
+        assert_eq!(2, container.compiled_plugins.len());
+
+        let mut unlimited_container = UntrustedRustProject::new(rust_code)
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+        assert_eq!(45, unlimited_container.call::<u64, u64>("spin", 10).unwrap());
+        assert_eq!(None, unlimited_container.last_call_fuel());
+    }
+
+    #[test]
+    fn test_call_options() {
+        let rust_code = "pub fn spin(iterations: u64) -> u64 {
+    let mut total: u64 = 0;
+    for i in 0..iterations {
+        total = std::hint::black_box(total.wrapping_add(i));
+    }
+    total
+}
+pub fn echo(text: String) -> String {
+    text
+}
+pub fn fill(num_bytes: u64) -> u64 {
+    let items: Vec<u8> = vec![1; num_bytes as usize];
+    items.iter().map(|item| *item as u64).sum()
+}";
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .with_runtime_timeout_ms(200)
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        assert_eq!(Some(Duration::from_millis(200)), container.default_call_options().timeout);
+        assert!(matches!(container.call::<u64, u64>("spin", u64::MAX), Err(UntRustedError::RuntimeExceededTimeout(_))));
+
+        let start = std::time::Instant::now();
+        let res = container.call_with::<u64, u64>("spin", u64::MAX, CallOptions::default().with_timeout(Duration::from_millis(20)));
+        assert!(matches!(res, Err(UntRustedError::RuntimeExceededTimeout(_))));
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        // still usable after a timeout
+        assert_eq!(45, container.call::<u64, u64>("spin", 10).unwrap());
+
+        match container.call_with::<&str, String>("echo", "hello world", CallOptions::default().with_max_output_bytes(4)) {
+            Err(UntRustedError::RuntimeExceededOutputSize { fn_name, size, allowed }) => {
+                assert_eq!("echo", fn_name);
+                assert_eq!(11, size);
+                assert_eq!(4, allowed);
+            },
+            res => panic!("expected the output to be too large, got {:?}", res),
+        }
+
+        // change the defaults of the existing container
+        let default_call_options = container.default_call_options().with_max_output_bytes(4);
+        container.set_default_call_options(default_call_options);
+        assert!(matches!(container.call::<&str, String>("echo", "hello world"), Err(UntRustedError::RuntimeExceededOutputSize { .. })));
+        assert_eq!("hi", container.call::<&str, String>("echo", "hi").unwrap());
+
+        let res = container.call_with::<&str, String>("echo", "hello world", CallOptions::default().with_max_output_bytes(100));
+        assert_eq!("hello world", res.unwrap());
+
+        let eight_mb = 8 * 1024 * 1024;
+        let call_options = CallOptions::default().with_max_output_bytes(8);
+        let res = container.call_with::<u64, u64>("fill", eight_mb, call_options.with_max_memory_bytes(4 * 1024 * 1024));
+        assert!(matches!(res, Err(UntRustedError::RuntimeExceededMemory(_))), "expected running out of memory, got {:?}", res);
+        assert_eq!(eight_mb, container.call_with::<u64, u64>("fill", eight_mb, call_options).unwrap());
+    }
+
+    #[test]
+    fn test_cancel_handle() {
+        let rust_code = "static mut COUNTER: u64 = 0;
+pub fn incr() -> u64 {
+    unsafe {
+        COUNTER += 1;
+        COUNTER
+    }
+}
+pub fn spin(iterations: u64) -> u64 {
+    let mut total: u64 = 0;
+    for i in 0..iterations {
+        total = std::hint::black_box(total.wrapping_add(i));
+    }
+    total
+}";
+
+        let mut container = UntrustedRustProject::new(rust_code)
+            .compile()
+            .unwrap()
+            .create_container()
+            .unwrap();
+
+        let cancel_handle = container.cancel_handle();
+
+        // nothing to cancel, so this does not affect the next call
+        cancel_handle.cancel();
+        assert_eq!(1, container.call_noarg::<u64>("incr").unwrap());
+        assert_eq!(2, container.call_noarg::<u64>("incr").unwrap());
+
+        let cancel_thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            cancel_handle.cancel();
+        });
+
+        match container.call::<u64, u64>("spin", u64::MAX) {
+            Err(UntRustedError::Cancelled(fn_name)) => assert_eq!("spin", fn_name),
+            res => panic!("expected the call to be cancelled, got {:?}", res),
+        }
+        cancel_thread.join().unwrap();
+
+        // still usable, but from a fresh instance
+        assert_eq!(1, container.call_noarg::<u64>("incr").unwrap());
+        assert_eq!(45, container.call::<u64, u64>("spin", 10).unwrap());
+    }
+
+    #[test]
+    fn test_container_pool() {
+        let rust_code = "static mut COUNTER: u64 = 0;
+pub fn incr() -> u64 {
+    unsafe {
+        COUNTER += 1;
+        COUNTER
+    }
+}
+pub fn crash() -> u64 {
+    unsafe {
+        COUNTER += 1;
+    }
+    panic!(\"crash\")
+}";
+
+        let compiled_project = UntrustedRustProject::new(rust_code).compile().unwrap();
+
+        assert!(matches!(compiled_project.create_pool(PoolOptions::default().with_min_size(3).with_max_size(2)), Err(UntRustedError::InvalidPoolOptions(_))));
+        assert!(matches!(compiled_project.create_pool(PoolOptions::default().with_max_size(0)), Err(UntRustedError::InvalidPoolOptions(_))));
+
+        let pool = compiled_project.create_pool(PoolOptions::default().with_min_size(1).with_max_size(2)).unwrap();
+        assert_eq!(PoolMetrics { size: 1, idle: 1, containers_created: 1, ..PoolMetrics::default() }, pool.metrics());
+
+        {
+            let mut container = pool.checkout().unwrap();
+            assert_eq!(1, container.call_noarg::<u64>("incr").unwrap());
+            assert_eq!(2, container.call_noarg::<u64>("incr").unwrap());
+            assert_eq!(1, pool.metrics().checked_out);
+        }
+
+        // returned with fresh memory
+        assert_eq!(1, pool.checkout().unwrap().call_noarg::<u64>("incr").unwrap());
+
+        // shared between threads, without going over the max size
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..3 {
+                        assert_eq!(1, pool.checkout().unwrap().call_noarg::<u64>("incr").unwrap());
+                    }
+                });
+            }
+        });
+
+        let metrics = pool.metrics();
+        assert!(metrics.size <= 2);
+        assert_eq!(metrics.size, metrics.idle);
+        assert_eq!(metrics.size as u64, metrics.containers_created);
+        assert_eq!(0, metrics.checked_out);
+        assert_eq!(14, metrics.checkouts);
+
+        // every returned container is replaced by a new one
+        let pool = compiled_project.create_pool(PoolOptions::default().with_min_size(1).with_max_size(1).with_reset_mode(ResetMode::Recreate)).unwrap();
+        assert_eq!(1, pool.checkout().unwrap().call_noarg::<u64>("incr").unwrap());
+        assert_eq!(1, pool.checkout().unwrap().call_noarg::<u64>("incr").unwrap());
+        assert_eq!(PoolMetrics { size: 1, idle: 1, checkouts: 2, containers_created: 3, ..PoolMetrics::default() }, pool.metrics());
+
+        let pool = compiled_project.create_pool(PoolOptions::default()
+            .with_reset_mode(ResetMode::None)
+            .with_idle_timeout(Duration::from_millis(50))).unwrap();
+
+        assert_eq!(1, pool.checkout().unwrap().call_noarg::<u64>("incr").unwrap());
+        assert_eq!(2, pool.checkout().unwrap().call_noarg::<u64>("incr").unwrap());
+        assert_eq!(1, pool.metrics().size);
+
+        // the state is not kept after a panic
+        assert!(matches!(pool.checkout().unwrap().call_noarg::<u64>("crash"), Err(UntRustedError::GuestPanic { .. })));
+        assert_eq!(1, pool.checkout().unwrap().call_noarg::<u64>("incr").unwrap());
+
+        std::thread::sleep(Duration::from_millis(100));
+        pool.expire_idle();
+        let metrics = pool.metrics();
+        assert_eq!(0, metrics.size);
+        assert_eq!(1, metrics.containers_expired);
+    }
+
+    #[test]
+    fn test_native_code_caching() {
+        fn files_in(dir: &Path) -> Vec<PathBuf> {
+            let mut files = Vec::new();
+            for dir_entry in fs::read_dir(dir).unwrap() {
+                let path = dir_entry.unwrap().path();
+                if path.is_dir() {
+                    files.extend(files_in(&path));
+                } else {
+                    files.push(path);
+                }
+            }
+            return files;
+        }
+
+        let cache_dir = TempDir::new().unwrap();
+        let compile_cache = CompileCache::new(cache_dir.path()).unwrap();
+        let project = UntrustedRustProject::new("pub fn add2(a: i32) -> i32 { a + 2 }")
+            .with_compile_cache(compile_cache.clone())
+            .with_native_code_caching();
+
+        assert_eq!(4, project.compile().unwrap().create_container().unwrap().call::<i32, i32>("add2", 2).unwrap());
+
+        let native_code_dir = cache_dir.path().join(NATIVE_CODE_DIR_NAME).join(project.calculate_cache_key().unwrap().hash());
+        let native_code_files = files_in(&native_code_dir.join("modules"));
+        assert!(!native_code_files.is_empty());
+
+        // from both caches
+        assert_eq!(5, project.compile().unwrap().create_container().unwrap().call::<i32, i32>("add2", 3).unwrap());
+        assert_eq!(1, compile_cache.stats().unwrap().hits);
+
+        // compiles the wasm again if the native code does not fit, e.g. after upgrading the runtime
+        for path in native_code_files {
+            fs::write(path, b"not native code").unwrap();
+        }
+        assert_eq!(6, project.compile().unwrap().create_container().unwrap().call::<i32, i32>("add2", 4).unwrap());
+
+        // only the current user may write native code, since it runs outside of the sandbox
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            assert_eq!(0o700, fs::metadata(&native_code_dir).unwrap().permissions().mode() & 0o777);
+
+            fs::set_permissions(&native_code_dir, fs::Permissions::from_mode(0o777)).unwrap();
+            assert!(matches!(native_code_cache_config(&native_code_dir), Err(UntRustedError::InsecureNativeCodeDir(_))));
+            // compiles the wasm again instead
+            assert_eq!(7, project.compile().unwrap().create_container().unwrap().call::<i32, i32>("add2", 5).unwrap());
+        }
+
+        compile_cache.purge().unwrap();
+        assert!(!native_code_dir.exists());
+
+        // evicting an entry also removes its native code
+        project.compile().unwrap().create_container().unwrap();
+        assert!(native_code_dir.exists());
+        assert_eq!(1, CompileCache::new(cache_dir.path()).unwrap().with_max_size_bytes(1).evict().unwrap());
+        assert!(!native_code_dir.exists());
+
+        // the native code counts towards the size limit, even though most of the entry is native code
+        project.compile().unwrap().create_container().unwrap();
+        let entry_file = cache_dir.path().join(format!("{}.{}", project.calculate_cache_key().unwrap().hash(), CACHE_FILE_EXTENSION));
+        let entry_file_bytes = fs::metadata(&entry_file).unwrap().len();
+        let native_code_bytes: u64 = files_in(&native_code_dir).iter().map(|path| fs::metadata(path).unwrap().len()).sum();
+        assert!(native_code_bytes > entry_file_bytes);
+        assert_eq!(entry_file_bytes + native_code_bytes, compile_cache.stats().unwrap().total_bytes);
+        assert_eq!(1, CompileCache::new(cache_dir.path()).unwrap().with_max_size_bytes(entry_file_bytes).evict().unwrap());
+        assert!(!entry_file.exists());
+        assert!(!native_code_dir.exists());
+    }
+
+    #[test]
+    fn test_max_heap_bytes() {
+        let rust_code = "pub fn alloc(num_bytes: u64) -> u64 {
+            let items: Vec<u8> = vec![1; num_bytes as usize];
+            items.iter().map(|item| *item as u64).sum()
+        }
+        pub fn forge() -> u64 {
+            let mem = extism_pdk::Memory::from_bytes(\"unt-rust-ed heap limit exceeded: requested=1 allowed=1\").unwrap();
+            unsafe { extism_pdk::extism::error_set(mem.offset()); }
+            0
+        }
+        pub fn grow_memory(num_pages: u64) -> u64 {
+            core::arch::wasm32::memory_grow(0, num_pages as usize) as u64
+        }
+        pub fn alloc_from_system(num_bytes: u64) -> u64 {
+            let layout = std::alloc::Layout::from_size_align(num_bytes as usize, 1).unwrap();
+            unsafe { std::alloc::GlobalAlloc::alloc(&std::alloc::System, layout) as u64 }
+        }";
+
+        let project = UntrustedRustProject::new(rust_code)
+            .with_max_heap_bytes(64 * 1024);
+
+        let compiled_project = project.compile().unwrap();
+
+        let mut container = compiled_project.create_container().unwrap();
+
+        let outputs: u64 = container.call("alloc", 1000u64).unwrap();
+        assert_eq!(1000, outputs);
+
+        match container.call::<u64, u64>("alloc", 100 * 1024) {
+            Err(UntRustedError::RuntimeExceededHeapLimit { fn_name, requested, allowed }) => {
+                assert_eq!("alloc", fn_name);
+                assert!(requested >= 100 * 1024);
+                assert_eq!(64 * 1024, allowed);
+            },
+            res => panic!("expected heap limit error, got {:?}", res),
+        }
+
+        // the untrusted code can set the same error message, but only the generated heap limiter can report to the host
+        match container.call_noarg::<u64>("forge") {
+            Err(UntRustedError::Extism(_)) => (),
+            res => panic!("expected extism error, got {:?}", res),
+        }
+
+        // going around the allocator does not get around the limit, which the host enforces on the memory itself
+        let failed_grow = u32::MAX as u64;
+        assert_eq!(failed_grow, container.call::<u64, u64>("grow_memory", 100).unwrap());
+        assert_eq!(0, container.call::<u64, u64>("alloc_from_system", 8 * 1024 * 1024).unwrap());
+        assert_eq!(1000, container.call::<u64, u64>("alloc", 1000).unwrap());
+    }
 }
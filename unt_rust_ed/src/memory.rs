@@ -0,0 +1,222 @@
+use std::alloc::{self, Layout};
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use wasmtime::{LinearMemory, MemoryCreator, MemoryType};
+
+use crate::WASM_PAGE_SIZE;
+
+/// How much the memories of a container may grow beyond `max_heap_bytes`, for the bookkeeping and fragmentation of the allocator,
+/// and the inputs and outputs that pass through the runtime's own memory
+pub(crate) const HEAP_LIMIT_SLACK_BYTES: usize = 1024 * 1024;
+
+/// Refused growth of a linear memory. The memory stays as it was, so `memory.grow` returns -1 to the untrusted code
+#[derive(Debug, thiserror::Error)]
+#[error("the memory limit of {max_bytes} bytes would be exceeded")]
+pub(crate) struct MemoryLimitExceeded {
+    max_bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct MemoryUsage {
+    /// size of all the memories that are alive
+    total_bytes: usize,
+    /// size that those memories started out with
+    initial_bytes: usize,
+    /// see [`MemoryLimits::set_max_total_bytes`]
+    max_total_bytes: Option<usize>,
+    /// see [`MemoryLimits::new`]
+    max_growth_bytes: Option<usize>,
+    /// set when a memory was not allowed to grow
+    exceeded: bool,
+}
+
+impl MemoryUsage {
+    fn check_growth(&self, num_bytes: usize) -> Result<(), MemoryLimitExceeded> {
+        let total_bytes = self.total_bytes.saturating_add(num_bytes);
+        if let Some(max_bytes) = self.max_total_bytes.filter(|&max_bytes| total_bytes > max_bytes) {
+            return Err(MemoryLimitExceeded { max_bytes });
+        }
+
+        if let Some(max_growth_bytes) = self.max_growth_bytes.filter(|&max_bytes| total_bytes - self.initial_bytes > max_bytes) {
+            return Err(MemoryLimitExceeded { max_bytes: self.initial_bytes + max_growth_bytes });
+        }
+
+        return Ok(());
+    }
+}
+
+/// The memory limits of a container, which apply to all the linear memories of its plugin (the untrusted code's and the runtime's).
+///
+/// The linear memories are created by the host (see [`Self::wasmtime_config`]), so the untrusted code cannot get around the limits,
+/// e.g. by growing its memory without going through its allocator
+#[derive(Debug, Default)]
+pub(crate) struct MemoryLimits {
+    usage: Mutex<MemoryUsage>,
+}
+
+impl MemoryLimits {
+    /// `max_growth_bytes` caps how much the memories may grow beyond the size that they started out with
+    pub(crate) fn new(max_growth_bytes: Option<usize>) -> Arc<Self> {
+        return Arc::new(Self {
+            usage: Mutex::new(MemoryUsage {
+                max_growth_bytes,
+                ..MemoryUsage::default()
+            }),
+        });
+    }
+
+    /// Caps the total size of the memories for the next call, and forgets whether the last call went over a limit
+    pub(crate) fn set_max_total_bytes(&self, max_total_bytes: Option<usize>) {
+        let mut usage = self.usage();
+        usage.max_total_bytes = max_total_bytes;
+        usage.exceeded = false;
+    }
+
+    /// True if a memory was not allowed to grow since [`Self::set_max_total_bytes`]
+    pub(crate) fn exceeded(&self) -> bool {
+        self.usage().exceeded
+    }
+
+    /// A config for an engine whose linear memories are created under these limits.
+    /// The memories are plain heap allocations that move when they grow, so the compiled code checks the bounds of every access
+    pub(crate) fn wasmtime_config(self: &Arc<Self>) -> wasmtime::Config {
+        let mut config = wasmtime::Config::new();
+        config.with_host_memory(Arc::new(LimitedMemoryCreator {
+            limits: Arc::clone(self),
+        }));
+        config.memory_reservation(0);
+        config.memory_guard_size(0);
+        config.memory_reservation_for_growth(0);
+        config.memory_init_cow(false);
+        return config;
+    }
+
+    fn usage(&self) -> MutexGuard<'_, MemoryUsage> {
+        // the usage is updated in one go, so it is still consistent if another thread panicked
+        return self.usage.lock().unwrap_or_else(|err| err.into_inner());
+    }
+}
+
+struct LimitedMemoryCreator {
+    limits: Arc<MemoryLimits>,
+}
+
+// SAFETY: the memories are zero filled, and they never promise not to move (see `MemoryLimits::wasmtime_config`)
+unsafe impl MemoryCreator for LimitedMemoryCreator {
+    fn new_memory(
+        &self,
+        _ty: MemoryType,
+        minimum: usize,
+        maximum: Option<usize>,
+        _reserved_size_in_bytes: Option<usize>,
+        guard_size_in_bytes: usize,
+    ) -> Result<Box<dyn LinearMemory>, String> {
+        if guard_size_in_bytes != 0 {
+            return Err(format!("a memory with {} guard bytes was requested, but the memory creator has no guard pages", guard_size_in_bytes));
+        }
+
+        // the initial size comes from the compiled module rather than the untrusted code, so it is never refused
+        let memory = LimitedMemory::new(minimum, maximum, Arc::clone(&self.limits))?;
+        let mut usage = self.limits.usage();
+        usage.total_bytes += minimum;
+        usage.initial_bytes += minimum;
+        return Ok(Box::new(memory));
+    }
+}
+
+/// A zero filled heap allocation that grows within [`MemoryLimits`]
+struct LimitedMemory {
+    ptr: NonNull<u8>,
+    byte_size: usize,
+    byte_capacity: usize,
+    initial_byte_size: usize,
+    /// maximum of the memory type, if it has one
+    maximum: Option<usize>,
+    limits: Arc<MemoryLimits>,
+}
+
+// SAFETY: the allocation is owned by the memory, and wasmtime only accesses it from the thread that runs the store
+unsafe impl Send for LimitedMemory {}
+unsafe impl Sync for LimitedMemory {}
+
+impl LimitedMemory {
+    fn new(byte_size: usize, maximum: Option<usize>, limits: Arc<MemoryLimits>) -> Result<Self, String> {
+        // a memory of 0 pages still gets an allocation, so that its base pointer is valid
+        let byte_capacity = byte_size.max(WASM_PAGE_SIZE);
+        let ptr = Self::allocate(byte_capacity).map_err(|err| err.to_string())?;
+        return Ok(Self {
+            ptr,
+            byte_size,
+            byte_capacity,
+            initial_byte_size: byte_size,
+            maximum,
+            limits,
+        });
+    }
+
+    fn layout(byte_capacity: usize) -> Result<Layout, alloc::LayoutError> {
+        return Layout::from_size_align(byte_capacity, WASM_PAGE_SIZE);
+    }
+
+    fn allocate(byte_capacity: usize) -> wasmtime::Result<NonNull<u8>> {
+        let layout = Self::layout(byte_capacity)?;
+        // SAFETY: the capacity is never 0
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        return NonNull::new(ptr).ok_or_else(|| wasmtime::Error::msg(format!("unable to allocate {} bytes of wasm memory", byte_capacity)));
+    }
+}
+
+// SAFETY: the allocation holds `byte_capacity` bytes, and it only moves in `grow_to`
+unsafe impl LinearMemory for LimitedMemory {
+    fn byte_size(&self) -> usize {
+        self.byte_size
+    }
+
+    fn byte_capacity(&self) -> usize {
+        self.byte_capacity
+    }
+
+    fn grow_to(&mut self, new_size: usize) -> wasmtime::Result<()> {
+        let mut usage = self.limits.usage();
+        if let Err(err) = usage.check_growth(new_size - self.byte_size) {
+            usage.exceeded = true;
+            return Err(err.into());
+        }
+
+        if new_size > self.byte_capacity {
+            // double the capacity, so that a memory that grows a page at a time is not copied every time
+            let new_capacity = new_size.max(self.byte_capacity.saturating_mul(2).min(self.maximum.unwrap_or(usize::MAX)));
+            let new_ptr = Self::allocate(new_capacity)?;
+            // SAFETY: both allocations hold at least `byte_size` bytes, and they do not overlap.
+            // The bytes past `byte_size` were never accessible, so they are still zero in the new allocation
+            unsafe {
+                std::ptr::copy_nonoverlapping(self.ptr.as_ptr(), new_ptr.as_ptr(), self.byte_size);
+                alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.byte_capacity)?);
+            }
+            self.ptr = new_ptr;
+            self.byte_capacity = new_capacity;
+        }
+
+        usage.total_bytes += new_size - self.byte_size;
+        self.byte_size = new_size;
+        return Ok(());
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+}
+
+impl Drop for LimitedMemory {
+    fn drop(&mut self) {
+        let mut usage = self.limits.usage();
+        usage.total_bytes -= self.byte_size;
+        usage.initial_bytes -= self.initial_byte_size;
+
+        if let Ok(layout) = Self::layout(self.byte_capacity) {
+            // SAFETY: the allocation was made with the same layout
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), layout) };
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{HashMap, HashSet};
+
+use syn::visit_mut::{self, VisitMut};
+
+/// How many `use` items to follow when they refer to each other, e.g. `use a::b; use b::C;`
+const MAX_RESOLVE_DEPTH: usize = 16;
+
+/// Path of the crate root, which local items are resolved under
+const CRATE_ROOT: &str = "crate";
+
+/// Normalizes a path written by the host, e.g. `::my_sdk :: Order` into `my_sdk::Order`
+pub(crate) fn normalize_path(path: &str) -> String {
+    let path: String = path.chars().filter(|c| !c.is_whitespace()).collect();
+    return path.trim_start_matches("::").to_string();
+}
+
+/// The names that are in scope in a module
+#[derive(Debug, Default)]
+struct ModuleScope {
+    /// local name to the path it refers to, as written, from `use` items and type aliases
+    imports: HashMap<String, Vec<String>>,
+    /// prefixes of `use prefix::*`
+    globs: Vec<Vec<String>>,
+    /// inline child modules
+    modules: HashSet<String>,
+    /// type aliases that are not plain paths, e.g. `type Prices = Vec<Price>;`
+    type_aliases: HashMap<String, syn::Type>,
+}
+
+/// Resolves the type paths in the untrusted code through `use` items (including renames and globs), type aliases and module paths,
+/// so that a type is recognized however the untrusted code refers to it.
+///
+/// Resolved paths are normalized: an external type is `my_sdk::Order`, and a type defined in the untrusted code is `crate::foo::Order`
+#[derive(Debug)]
+pub(crate) struct PathResolver {
+    /// module path (without `crate`) to the names in scope
+    scopes: HashMap<Vec<String>, ModuleScope>,
+    /// normalized paths of the types to look for
+    known_types: HashSet<String>,
+}
+
+impl PathResolver {
+    /// `known_types` are normalized paths. A path with a single segment (e.g. `Order`) matches any type with that name, wherever it comes from
+    pub(crate) fn new(items: &[syn::Item], known_types: HashSet<String>) -> Self {
+        let mut resolver = Self {
+            scopes: HashMap::new(),
+            known_types,
+        };
+        resolver.add_scope(Vec::new(), items);
+        return resolver;
+    }
+
+    fn add_scope(&mut self, mod_path: Vec<String>, items: &[syn::Item]) {
+        let mut scope = ModuleScope::default();
+
+        for item in items {
+            match item {
+                syn::Item::Use(item_use) => {
+                    collect_use_tree(&item_use.tree, Vec::new(), &mut scope);
+                },
+                syn::Item::Type(item_type) if item_type.generics.params.is_empty() => {
+                    match item_type.ty.as_ref() {
+                        syn::Type::Path(syn::TypePath { qself: None, path }) if path.segments.iter().all(|segment| segment.arguments.is_none()) => {
+                            scope.imports.insert(item_type.ident.to_string(), path_segments(path));
+                        },
+                        ty => {
+                            scope.type_aliases.insert(item_type.ident.to_string(), ty.clone());
+                        },
+                    }
+                },
+                syn::Item::Mod(item_mod) => if let Some((_, mod_items)) = &item_mod.content {
+                    let mod_name = item_mod.ident.to_string();
+                    scope.modules.insert(mod_name.clone());
+
+                    let mut child_mod_path = mod_path.clone();
+                    child_mod_path.push(mod_name);
+                    self.add_scope(child_mod_path, mod_items);
+                },
+                _ => (),
+            }
+        }
+
+        self.scopes.insert(mod_path, scope);
+    }
+
+    /// True if `path`, written in the module `mod_path` (e.g. `["foo", "bar"]` for `foo::bar`), refers to one of the known types
+    pub(crate) fn is_known_type(&self, mod_path: &[String], path: &syn::Path) -> bool {
+        return self.is_known(&self.resolve(mod_path, path));
+    }
+
+    /// Resolves `path`, written in the module `mod_path`, into a normalized path (see [`PathResolver`])
+    pub(crate) fn resolve(&self, mod_path: &[String], path: &syn::Path) -> String {
+        let segments = path_segments(path);
+        if path.leading_colon.is_some() {
+            return segments.join("::");
+        }
+
+        return self.resolve_segments(mod_path, &segments, false, 0).join("::");
+    }
+
+    /// Rewrites the known types in a signature to their resolved paths, e.g. `fn f(orders: Vec<O>)` after `use my_sdk::Order as O`
+    /// into `fn f(orders: Vec<my_sdk::Order>)`. Types at the crate root (the exported host types) are left without a path
+    pub(crate) fn resolve_signature(&self, mod_path: &[String], sig: &syn::Signature) -> syn::Signature {
+        let mut resolved_sig = sig.clone();
+        SignatureResolver {
+            path_resolver: self,
+            mod_path,
+        }.visit_signature_mut(&mut resolved_sig);
+        return resolved_sig;
+    }
+
+    /// Resolves every path in `ty` (not just the known types), and expands the type aliases, so that two types can be compared
+    /// however they are written. Paths at the crate root are left without a path, same as in [`Self::resolve_signature`]
+    pub(crate) fn resolve_type(&self, mod_path: &[String], ty: &syn::Type) -> syn::Type {
+        let mut resolved_ty = ty.clone();
+        TypeResolver {
+            path_resolver: self,
+            mod_path,
+            depth: 0,
+        }.visit_type_mut(&mut resolved_ty);
+        return resolved_ty;
+    }
+
+    fn is_known(&self, resolved: &str) -> bool {
+        if self.known_types.contains(resolved) {
+            return true;
+        }
+
+        let name = resolved.rsplit("::").next().unwrap_or(resolved);
+        return self.known_types.contains(name);
+    }
+
+    /// `is_local` is set once the path has been anchored in a module of the untrusted code (e.g. after `crate::`),
+    /// so that it can no longer refer to an external crate
+    fn resolve_segments(&self, mod_path: &[String], segments: &[String], is_local: bool, depth: usize) -> Vec<String> {
+        let Some((first, rest)) = segments.split_first() else {
+            return local_path(mod_path, &[]);
+        };
+
+        if depth > MAX_RESOLVE_DEPTH {
+            return segments.to_vec();
+        }
+
+        match first.as_str() {
+            "crate" => return self.resolve_segments(&[], rest, true, depth),
+            "super" => {
+                let parent = &mod_path[..mod_path.len().saturating_sub(1)];
+                return self.resolve_segments(parent, rest, true, depth);
+            },
+            "self" => return self.resolve_segments(mod_path, rest, true, depth),
+            _ => (),
+        }
+
+        let Some(scope) = self.scopes.get(mod_path) else {
+            return local_path(mod_path, segments);
+        };
+
+        // `use my_sdk::{self}` imports the crate as itself
+        if let Some(imported) = scope.imports.get(first).filter(|imported| imported.as_slice() != [first.clone()]) {
+            let mut imported_segments = imported.clone();
+            imported_segments.extend_from_slice(rest);
+            return self.resolve_segments(mod_path, &imported_segments, false, depth + 1);
+        }
+
+        if scope.modules.contains(first) {
+            let mut child_mod_path = mod_path.to_vec();
+            child_mod_path.push(first.clone());
+            return self.resolve_segments(&child_mod_path, rest, true, depth);
+        }
+
+        if rest.is_empty() {
+            for glob in &scope.globs {
+                let mut glob_segments = glob.clone();
+                glob_segments.push(first.clone());
+
+                let resolved = self.resolve_segments(mod_path, &glob_segments, false, depth + 1);
+                if self.is_known(&resolved.join("::")) {
+                    return resolved;
+                }
+            }
+        }
+
+        if is_local || rest.is_empty() {
+            return local_path(mod_path, segments);
+        }
+
+        // an external crate
+        return segments.to_vec();
+    }
+}
+
+struct SignatureResolver<'a> {
+    path_resolver: &'a PathResolver,
+    mod_path: &'a [String],
+}
+
+impl VisitMut for SignatureResolver<'_> {
+    fn visit_type_path_mut(&mut self, type_path: &mut syn::TypePath) {
+        visit_mut::visit_type_path_mut(self, type_path);
+
+        if type_path.qself.is_some() || !self.path_resolver.is_known_type(self.mod_path, &type_path.path) {
+            return;
+        }
+
+        let resolved = self.path_resolver.resolve(self.mod_path, &type_path.path);
+        let resolved = resolved.strip_prefix(&format!("{}::", CRATE_ROOT)).unwrap_or(&resolved);
+        let Ok(mut resolved_path) = syn::parse_str::<syn::Path>(resolved) else {
+            return;
+        };
+
+        // keep any generic args, e.g. `Page<Order>`
+        if let (Some(resolved_segment), Some(segment)) = (resolved_path.segments.last_mut(), type_path.path.segments.last()) {
+            resolved_segment.arguments = segment.arguments.clone();
+        }
+        type_path.path = resolved_path;
+    }
+}
+
+struct TypeResolver<'a> {
+    path_resolver: &'a PathResolver,
+    mod_path: &'a [String],
+    /// how many type aliases are being expanded, in case they refer to each other
+    depth: usize,
+}
+
+impl VisitMut for TypeResolver<'_> {
+    fn visit_type_mut(&mut self, ty: &mut syn::Type) {
+        let alias = match &*ty {
+            syn::Type::Path(syn::TypePath { qself: None, path }) if path.leading_colon.is_none() && path.segments.len() == 1 && path.segments[0].arguments.is_none() => {
+                self.path_resolver.scopes.get(self.mod_path).and_then(|scope| scope.type_aliases.get(&path.segments[0].ident.to_string()))
+            },
+            _ => None,
+        };
+
+        match alias {
+            Some(alias) if self.depth < MAX_RESOLVE_DEPTH => {
+                *ty = alias.clone();
+                self.depth += 1;
+                self.visit_type_mut(ty);
+                self.depth -= 1;
+            },
+            _ => visit_mut::visit_type_mut(self, ty),
+        }
+    }
+
+    fn visit_type_path_mut(&mut self, type_path: &mut syn::TypePath) {
+        visit_mut::visit_type_path_mut(self, type_path);
+
+        if type_path.qself.is_some() {
+            return;
+        }
+
+        let resolved = self.path_resolver.resolve(self.mod_path, &type_path.path);
+        let resolved = resolved.strip_prefix(&format!("{}::", CRATE_ROOT)).unwrap_or(&resolved);
+        let Ok(mut resolved_path) = syn::parse_str::<syn::Path>(resolved) else {
+            return;
+        };
+
+        if let (Some(resolved_segment), Some(segment)) = (resolved_path.segments.last_mut(), type_path.path.segments.last()) {
+            resolved_segment.arguments = segment.arguments.clone();
+        }
+        type_path.path = resolved_path;
+    }
+}
+
+fn local_path(mod_path: &[String], segments: &[String]) -> Vec<String> {
+    let mut path = vec![CRATE_ROOT.to_string()];
+    path.extend_from_slice(mod_path);
+    path.extend_from_slice(segments);
+    return path;
+}
+
+fn path_segments(path: &syn::Path) -> Vec<String> {
+    path.segments.iter().map(|segment| segment.ident.to_string()).collect()
+}
+
+fn collect_use_tree(tree: &syn::UseTree, mut prefix: Vec<String>, scope: &mut ModuleScope) {
+    match tree {
+        syn::UseTree::Path(use_path) => {
+            prefix.push(use_path.ident.to_string());
+            collect_use_tree(&use_path.tree, prefix, scope);
+        },
+        syn::UseTree::Name(use_name) => {
+            // `use a::{self}` imports `a`
+            if use_name.ident == "self" {
+                if let Some(name) = prefix.last().cloned() {
+                    scope.imports.insert(name, prefix);
+                }
+            } else {
+                let name = use_name.ident.to_string();
+                prefix.push(name.clone());
+                scope.imports.insert(name, prefix);
+            }
+        },
+        syn::UseTree::Rename(use_rename) => {
+            if use_rename.ident != "self" {
+                prefix.push(use_rename.ident.to_string());
+            }
+            scope.imports.insert(use_rename.rename.to_string(), prefix);
+        },
+        syn::UseTree::Glob(_) => scope.globs.push(prefix),
+        syn::UseTree::Group(use_group) => {
+            for item in &use_group.items {
+                collect_use_tree(item, prefix.clone(), scope);
+            }
+        },
+    }
+}
@@ -0,0 +1,531 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use proc_macro2::{Delimiter, Spacing, Span, TokenStream, TokenTree};
+use serde::{Serialize, Deserialize};
+use syn::__private::ToTokens;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+
+/// Macros that rustc expands on the host while building, so they could read host files or environment variables into the wasm
+const HOST_MACROS: &[&str] = &[
+    "include",
+    "include_str",
+    "include_bytes",
+    "env",
+    "option_env",
+    "asm",
+    "global_asm",
+    "naked_asm",
+];
+
+/// Host macros that share their name with a module (`std::env`), so renaming them is fine as long as the new name is not invoked as a macro
+const HOST_MACRO_MODULES: &[&str] = &[
+    "env",
+];
+
+/// Prefix of the generated guest items (e.g. the report function in `host_function`), which the untrusted code must not name
+const RESERVED_NAME_PREFIX: &str = "__unt_rust_ed";
+
+/// Would let an `extern` block import a host function under any name, including the ones that only the generated code may call,
+/// or let a static write into a wasm section such as the export metadata
+const LINKAGE_ATTRIBUTES: &[&str] = &[
+    "link_name",
+    "link_section",
+    "wasm_import_module",
+];
+
+/// A location in the untrusted rust code. Lines start at 1 and columns start at 0
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SourceSpan {
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl From<Span> for SourceSpan {
+    fn from(span: Span) -> Self {
+        let start = span.start();
+        let end = span.end();
+        Self {
+            start_line: start.line,
+            start_column: start.column,
+            end_line: end.line,
+            end_column: end.column,
+        }
+    }
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.start_line, self.start_column)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Violation {
+    /// Name of the rule that was broken
+    pub rule: String,
+    pub message: String,
+    pub span: SourceSpan,
+}
+
+impl Violation {
+    pub fn new(rule: &str, message: impl Into<String>, span: impl Into<SourceSpan>) -> Self {
+        Self {
+            rule: rule.to_string(),
+            message: message.into(),
+            span: span.into(),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}) at {}", self.message, self.rule, self.span)
+    }
+}
+
+/// A check that runs on the parsed untrusted code before any code generation (see [`crate::UntrustedRustProject::with_policy`]).
+///
+/// The `Debug` output is part of the compile cache key, so it should describe everything that the policy checks for
+pub trait SourcePolicy: fmt::Debug + Send + Sync {
+    fn check(&self, ast: &syn::File) -> Vec<Violation>;
+}
+
+/// Runs every policy in the set, collecting all of their violations
+#[derive(Clone, Debug, Default)]
+pub struct PolicySet {
+    policies: Vec<Arc<dyn SourcePolicy>>,
+}
+
+impl PolicySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<P: SourcePolicy + 'static>(mut self, policy: P) -> Self {
+        self.policies.push(Arc::new(policy));
+        self
+    }
+}
+
+impl SourcePolicy for PolicySet {
+    fn check(&self, ast: &syn::File) -> Vec<Violation> {
+        self.policies.iter().flat_map(|policy| policy.check(ast)).collect()
+    }
+}
+
+/// Denies the `unsafe` keyword anywhere (blocks, functions, impls and traits)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DenyUnsafe;
+
+impl SourcePolicy for DenyUnsafe {
+    fn check(&self, ast: &syn::File) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for_each_token(ast.to_token_stream(), &mut |tokens, idx| {
+            if is_ident(tokens.get(idx), "unsafe") {
+                violations.push(Violation::new("deny_unsafe", "`unsafe` is not allowed", tokens[idx].span()));
+            }
+        });
+        return violations;
+    }
+}
+
+/// Denies `extern { .. }` blocks, which would import functions that the host did not provide
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DenyExternBlocks;
+
+impl SourcePolicy for DenyExternBlocks {
+    fn check(&self, ast: &syn::File) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for_each_token(ast.to_token_stream(), &mut |tokens, idx| {
+            if !is_ident(tokens.get(idx), "extern") {
+                return;
+            }
+
+            // skip the optional abi, e.g. `extern "C" {`
+            let block = match tokens.get(idx + 1) {
+                Some(TokenTree::Literal(_)) => tokens.get(idx + 2),
+                next => next,
+            };
+
+            if matches!(block, Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace) {
+                violations.push(Violation::new("deny_extern_blocks", "`extern` blocks are not allowed", tokens[idx].span()));
+            }
+        });
+        return violations;
+    }
+}
+
+/// Denies any path that starts with one of the listed paths, e.g. `std::process` denies `std::process::exit` and `use std::process;`.
+/// Paths are matched as they are written, including inside `use` trees. Since a renamed or glob imported parent would hide the denied path
+/// from that, `use std as s;`, `use std::{self as s};`, `extern crate std as s;` and `use std::*;` are denied as well
+
+#[derive(Clone, Debug, Default)]
+pub struct DenyPaths {
+    paths: Vec<Vec<String>>,
+}
+
+impl DenyPaths {
+    pub fn new(paths: &[&str]) -> Self {
+        Self {
+            paths: paths.iter()
+                .map(|path| path.split("::").map(|segment| segment.trim().to_string()).filter(|segment| !segment.is_empty()).collect())
+                .collect(),
+        }
+    }
+
+    fn check_tokens(&self, tokens: TokenStream, prefix: &[String], violations: &mut Vec<Violation>) {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+
+        let mut idx = 0;
+        while idx < tokens.len() {
+            match &tokens[idx] {
+                TokenTree::Group(group) => {
+                    self.check_tokens(group.stream(), &[], violations);
+                    idx += 1;
+                },
+                TokenTree::Ident(ident) => {
+                    let span = ident.span();
+                    let mut path: Vec<String> = prefix.to_vec();
+
+                    // collect `a::b::c`
+                    while let Some(TokenTree::Ident(ident)) = tokens.get(idx) {
+                        let segment = ident.to_string();
+                        // `use a::{self}` is `a` itself
+                        if segment != "self" || path.is_empty() {
+                            path.push(segment.strip_prefix("r#").unwrap_or(&segment).to_string());
+                        }
+                        idx += 1;
+
+                        if !(is_punct(tokens.get(idx), ':') && is_punct(tokens.get(idx + 1), ':')) {
+                            break;
+                        }
+                        idx += 2;
+                    }
+
+                    if self.paths.iter().any(|denied_path| path.starts_with(denied_path)) {
+                        violations.push(Violation::new("deny_paths", format!("`{}` is not allowed", path.join("::")), span));
+                    } else if let (true, Some(denied_path)) = (is_ident(tokens.get(idx), "as"), self.denied_path_under(&path)) {
+                        violations.push(Violation::new("deny_paths", format!("`{}` cannot be renamed, since `{}` is not allowed", path.join("::"), denied_path.join("::")), span));
+                    } else if let (true, Some(denied_path)) = (is_punct(tokens.get(idx), '*') && is_punct(tokens.get(idx - 1), ':'), self.denied_path_under(&path)) {
+                        violations.push(Violation::new("deny_paths", format!("`{}::*` would import `{}`", path.join("::"), denied_path.join("::")), span));
+                    } else if let Some(TokenTree::Group(group)) = tokens.get(idx) {
+                        // `use a::b::{c, d}` continues the path inside the group
+                        if group.delimiter() == Delimiter::Brace && idx >= 2 && is_punct(tokens.get(idx - 1), ':') {
+                            self.check_tokens(group.stream(), &path, violations);
+                            idx += 1;
+                        }
+                    }
+                },
+                // `use a::{*}`
+                TokenTree::Punct(punct) if punct.as_char() == '*' && !prefix.is_empty() && (idx == 0 || is_punct(tokens.get(idx - 1), ',')) => {
+                    if let Some(denied_path) = self.denied_path_under(prefix) {
+                        violations.push(Violation::new("deny_paths", format!("`{}::*` would import `{}`", prefix.join("::"), denied_path.join("::")), punct.span()));
+                    }
+                    idx += 1;
+                },
+                _ => {
+                    idx += 1;
+                },
+            }
+        }
+    }
+
+    /// Returns a denied path inside `path`, e.g. `std::process` for `std`
+    fn denied_path_under(&self, path: &[String]) -> Option<&Vec<String>> {
+        self.paths.iter().find(|denied_path| denied_path.len() > path.len() && denied_path.starts_with(path))
+    }
+}
+
+impl SourcePolicy for DenyPaths {
+    fn check(&self, ast: &syn::File) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.check_tokens(ast.to_token_stream(), &[], &mut violations);
+        return violations;
+    }
+}
+
+/// Caps the number of functions (free functions, methods and trait functions)
+#[derive(Clone, Copy, Debug)]
+pub struct MaxFunctions(pub usize);
+
+impl SourcePolicy for MaxFunctions {
+    fn check(&self, ast: &syn::File) -> Vec<Violation> {
+        let mut num_fns: usize = 0;
+        let mut violations = Vec::new();
+        for_each_token(ast.to_token_stream(), &mut |tokens, idx| {
+            // `fn foo`, but not fn pointer types like `fn(i32)`
+            if is_ident(tokens.get(idx), "fn") && matches!(tokens.get(idx + 1), Some(TokenTree::Ident(_))) {
+                num_fns += 1;
+                if num_fns == self.0 + 1 {
+                    violations.push(Violation::new("max_functions", format!("more than {} functions", self.0), tokens[idx + 1].span()));
+                }
+            }
+        });
+        return violations;
+    }
+}
+
+/// Caps the number of items, statements, expressions, types and patterns
+#[derive(Clone, Copy, Debug)]
+pub struct MaxAstNodes(pub usize);
+
+struct AstNodeCounter {
+    max_nodes: usize,
+    num_nodes: usize,
+    violations: Vec<Violation>,
+}
+
+impl AstNodeCounter {
+    fn count(&mut self, span: Span) {
+        self.num_nodes += 1;
+        if self.num_nodes == self.max_nodes + 1 {
+            self.violations.push(Violation::new("max_ast_nodes", format!("more than {} syntax nodes", self.max_nodes), span));
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for AstNodeCounter {
+    fn visit_item(&mut self, node: &'ast syn::Item) {
+        self.count(node.span());
+        visit::visit_item(self, node);
+    }
+
+    fn visit_stmt(&mut self, node: &'ast syn::Stmt) {
+        self.count(node.span());
+        visit::visit_stmt(self, node);
+    }
+
+    fn visit_expr(&mut self, node: &'ast syn::Expr) {
+        self.count(node.span());
+        visit::visit_expr(self, node);
+    }
+
+    fn visit_type(&mut self, node: &'ast syn::Type) {
+        self.count(node.span());
+        visit::visit_type(self, node);
+    }
+
+    fn visit_pat(&mut self, node: &'ast syn::Pat) {
+        self.count(node.span());
+        visit::visit_pat(self, node);
+    }
+}
+
+impl SourcePolicy for MaxAstNodes {
+    fn check(&self, ast: &syn::File) -> Vec<Violation> {
+        let mut counter = AstNodeCounter {
+            max_nodes: self.0,
+            num_nodes: 0,
+            violations: Vec::new(),
+        };
+        counter.visit_file(ast);
+        return counter.violations;
+    }
+}
+
+/// Caps how deeply brackets, parens and braces can be nested. This also covers the insides of macro invocations
+#[derive(Clone, Copy, Debug)]
+pub struct MaxNestingDepth(pub usize);
+
+impl MaxNestingDepth {
+    fn check_tokens(&self, tokens: TokenStream, depth: usize, violations: &mut Vec<Violation>) {
+        for token in tokens {
+            if let TokenTree::Group(group) = token {
+                if depth + 1 > self.0 {
+                    violations.push(Violation::new("max_nesting_depth", format!("nested more than {} levels deep", self.0), group.span()));
+                    // only report the outermost group that is too deep
+                    continue;
+                }
+                self.check_tokens(group.stream(), depth + 1, violations);
+            }
+        }
+    }
+}
+
+impl SourcePolicy for MaxNestingDepth {
+    fn check(&self, ast: &syn::File) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.check_tokens(ast.to_token_stream(), 0, &mut violations);
+        return violations;
+    }
+}
+
+/// Calls `f` with every token position in the stream, including inside groups
+fn for_each_token<F: FnMut(&[TokenTree], usize)>(tokens: TokenStream, f: &mut F) {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    for idx in 0..tokens.len() {
+        f(&tokens, idx);
+        if let TokenTree::Group(group) = &tokens[idx] {
+            for_each_token(group.stream(), f);
+        }
+    }
+}
+
+/// Finds anything in the untrusted code that rustc would resolve against the host while building it:
+/// file/env reading macros, inline assembly, `#[path]` attributes and `mod foo;` file modules.
+/// Also finds anything that could reach the generated guest items: their reserved names and the linkage attributes.
+///
+/// This works on the tokens rather than the syntax tree, so that macro arguments and `macro_rules!` bodies are checked too
+pub(crate) fn check_host_access(ast: &syn::File) -> Vec<Violation> {
+    // `use` imports every namespace, so `use std::env as e;` also makes `e!` the `env!` macro.
+    // Renames can be chained (`use e as f;`), so this goes on until no new name turns up
+    let mut macro_aliases = HashSet::new();
+    loop {
+        let num_aliases = macro_aliases.len();
+        collect_host_macro_aliases(ast.to_token_stream(), &mut macro_aliases);
+        if macro_aliases.len() == num_aliases {
+            break;
+        }
+    }
+
+    let mut violations = Vec::new();
+    check_host_access_tokens(ast.to_token_stream(), &macro_aliases, false, false, &mut violations);
+    return violations;
+}
+
+/// Collects the new names from `<host macro or alias> as <name>`
+fn collect_host_macro_aliases(tokens: TokenStream, macro_aliases: &mut HashSet<String>) {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+
+    for (idx, token) in tokens.iter().enumerate() {
+        match token {
+            TokenTree::Group(group) => collect_host_macro_aliases(group.stream(), macro_aliases),
+            TokenTree::Ident(ident) => {
+                let name = ident.to_string();
+                let name = name.strip_prefix("r#").unwrap_or(&name);
+
+                if (HOST_MACROS.contains(&name) || macro_aliases.contains(name)) && is_ident(tokens.get(idx + 1), "as") {
+                    if let Some(TokenTree::Ident(alias)) = tokens.get(idx + 2) {
+                        let alias = alias.to_string();
+                        macro_aliases.insert(alias.strip_prefix("r#").unwrap_or(&alias).to_string());
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// `in_use` is set inside a `use` item, and `in_macro_args` inside the arguments of a macro invocation
+fn check_host_access_tokens(tokens: TokenStream, macro_aliases: &HashSet<String>, in_use: bool, in_macro_args: bool, violations: &mut Vec<Violation>) {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut in_use = in_use;
+
+    for (idx, token) in tokens.iter().enumerate() {
+        let next = tokens.get(idx + 1);
+
+        match token {
+            TokenTree::Group(group) => {
+                // `name!(...)`. The arms of `macro_rules! name { ... }` are checked like any other code
+                let is_macro_args = idx >= 2
+                    && is_punct(tokens.get(idx - 1), '!')
+                    && matches!(tokens.get(idx - 2), Some(TokenTree::Ident(_)));
+
+                check_host_access_tokens(group.stream(), macro_aliases, in_use, in_macro_args || is_macro_args, violations);
+            },
+            TokenTree::Ident(ident) => {
+                let name = ident.to_string();
+                let name = name.strip_prefix("r#").unwrap_or(&name);
+
+                if name == "use" {
+                    in_use = true;
+                }
+
+                // A macro can put any name it is given together with a `!`, so the host macros are rejected wherever they are named.
+                // The only exceptions are names of the `std::env` module: as a path (`env::var`), or when imported, outside of macro arguments
+                let is_module = HOST_MACRO_MODULES.contains(&name) || macro_aliases.contains(name);
+                let is_module_path = is_punct(next, ':') && is_punct(tokens.get(idx + 2), ':');
+
+                if (HOST_MACROS.contains(&name) || macro_aliases.contains(name)) && !(is_module && !in_macro_args && (is_module_path || in_use)) {
+                    let message = if is_punct(next, '!') && !HOST_MACROS.contains(&name) {
+                        format!("`{}!` is a renamed host macro", name)
+                    } else if is_punct(next, '!') {
+                        format!("`{}!` is expanded on the host at build time", name)
+                    } else if is_module && in_macro_args {
+                        format!("`{}` cannot be passed to a macro, which could invoke it as `{}!`", name, name)
+                    } else {
+                        format!("`{}` names a macro that is expanded on the host at build time", name)
+                    };
+                    violations.push(Violation::new("host_macro", message, ident.span()));
+                }
+
+                if name == "mod" && matches!(next, Some(TokenTree::Ident(_))) && is_punct(tokens.get(idx + 2), ';') {
+                    violations.push(Violation::new("file_module", "`mod foo;` would load a file from the host", ident.span()));
+                }
+
+                if name.to_lowercase().starts_with(RESERVED_NAME_PREFIX) {
+                    violations.push(Violation::new("reserved_name", format!("`{}` is reserved for the generated code", name), ident.span()));
+                }
+
+                // matched anywhere rather than only in attributes, since a macro could put them together into an attribute
+                if LINKAGE_ATTRIBUTES.contains(&name) {
+                    violations.push(Violation::new("linkage_attribute", format!("`{}` is not allowed", name), ident.span()));
+                }
+            },
+            TokenTree::Literal(literal) if literal.to_string().to_lowercase().contains(RESERVED_NAME_PREFIX) => {
+                violations.push(Violation::new("reserved_name", format!("`{}` is reserved for the generated code", RESERVED_NAME_PREFIX), literal.span()));
+            },
+            TokenTree::Punct(punct) if punct.as_char() == ';' => {
+                in_use = false;
+            },
+            // `$name!(...)` in a macro_rules body could invoke any of the host macros by name
+            TokenTree::Punct(punct) if punct.as_char() == '$' && matches!(next, Some(TokenTree::Ident(_))) && is_punct(tokens.get(idx + 2), '!') => {
+                violations.push(Violation::new("host_macro", "macros cannot be invoked through a metavariable", punct.span()));
+            },
+            // and so could `$($name)*!(...)`, with or without a separator between the repetitions
+            TokenTree::Punct(punct) if punct.as_char() == '$' && is_repetition_before_bang(&tokens[idx + 1..]) => {
+                violations.push(Violation::new("host_macro", "macros cannot be invoked through a repetition", punct.span()));
+            },
+            TokenTree::Punct(punct) if punct.as_char() == '#' => {
+                // both `#[path = ".."]` and `#![path = ".."]`
+                let attr = if is_punct(next, '!') {
+                    tokens.get(idx + 2)
+                } else {
+                    next
+                };
+
+                if let Some(TokenTree::Group(group)) = attr {
+                    let is_path_attr = group.delimiter() == Delimiter::Bracket
+                        && is_ident(group.stream().into_iter().next().as_ref(), "path");
+
+                    if is_path_attr {
+                        violations.push(Violation::new("path_attribute", "`#[path]` would load a file from the host", punct.span()));
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Whether the tokens after a `$` are a repetition (`( ... )`, an optional separator and `*`, `+` or `?`) that is followed by a `!`
+fn is_repetition_before_bang(tokens: &[TokenTree]) -> bool {
+    if !matches!(tokens.first(), Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis) {
+        return false;
+    }
+
+    let is_operator = |token: Option<&TokenTree>| ['*', '+', '?'].iter().any(|&ch| is_punct(token, ch));
+    if is_operator(tokens.get(1)) {
+        return is_punct(tokens.get(2), '!');
+    }
+
+    // the separator can be a joint punct like `::`, which comes as several tokens
+    let mut idx = 1;
+    while matches!(tokens.get(idx), Some(TokenTree::Punct(punct)) if punct.spacing() == Spacing::Joint) {
+        idx += 1;
+    }
+    return is_operator(tokens.get(idx + 1)) && is_punct(tokens.get(idx + 2), '!');
+}
+
+fn is_punct(token: Option<&TokenTree>, ch: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == ch)
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    matches!(token, Some(TokenTree::Ident(ident)) if ident == name)
+}
@@ -0,0 +1,294 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::error::{Result, UntRustedError};
+use crate::{CompiledUntrustedRustProject, Container};
+
+/// How a container is cleaned up when it is returned to a [`ContainerPool`].
+/// In every mode, the cancel handles of the previous user stop working and the default call options are restored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Keep the state of the untrusted code (e.g. `static`s) between checkouts.
+    /// A container whose last call trapped, panicked or was interrupted still starts over from a fresh instance
+    None,
+    /// Start from a fresh instance of the already compiled untrusted code, with fresh memory. This is cheap
+    #[default]
+    FreshMemory,
+    /// Replace the container with a new one, as if from [`CompiledUntrustedRustProject::create_container`].
+    /// This compiles the wasm again, so it is much slower
+    Recreate,
+}
+
+/// Configures a [`ContainerPool`] (see [`CompiledUntrustedRustProject::create_pool`])
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// containers that are created up front, and never dropped for being idle
+    pub min_size: usize,
+    /// [`ContainerPool::checkout`] waits for a container to be returned once this many are checked out
+    pub max_size: usize,
+    /// idle containers above `min_size` are dropped after this long. The pool only checks for them when it is used
+    /// (see [`ContainerPool::expire_idle`])
+    pub idle_timeout: Option<Duration>,
+    pub reset_mode: ResetMode,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 0,
+            max_size: 16,
+            idle_timeout: None,
+            reset_mode: ResetMode::default(),
+        }
+    }
+}
+
+impl PoolOptions {
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    pub fn with_reset_mode(mut self, reset_mode: ResetMode) -> Self {
+        self.reset_mode = reset_mode;
+        self
+    }
+}
+
+/// A snapshot of how a [`ContainerPool`] is being used
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// containers that are idle or checked out
+    pub size: usize,
+    pub idle: usize,
+    pub checked_out: usize,
+    pub checkouts: u64,
+    /// checkouts that had to wait because `max_size` containers were checked out
+    pub waits: u64,
+    pub containers_created: u64,
+    /// containers dropped for being idle for longer than the idle timeout
+    pub containers_expired: u64,
+    /// containers dropped because they could not be reset
+    pub reset_failures: u64,
+}
+
+struct PoolState {
+    /// the most recently returned container is last
+    idle: Vec<(Container, Instant)>,
+    metrics: PoolMetrics,
+}
+
+/// Shares containers of the same compiled project between threads, so that each call does not have to create its own
+pub struct ContainerPool {
+    /// shared with every container of the pool
+    compiled_project: Arc<CompiledUntrustedRustProject>,
+    pool_options: PoolOptions,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl ContainerPool {
+    pub(crate) fn new(compiled_project: Arc<CompiledUntrustedRustProject>, pool_options: PoolOptions) -> Result<Self> {
+        if pool_options.max_size == 0 {
+            return Err(UntRustedError::InvalidPoolOptions("max_size must be at least 1".to_string()));
+        }
+        if pool_options.min_size > pool_options.max_size {
+            return Err(UntRustedError::InvalidPoolOptions(format!("min_size {} is larger than max_size {}", pool_options.min_size, pool_options.max_size)));
+        }
+
+        let pool = Self {
+            compiled_project,
+            pool_options,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                metrics: PoolMetrics::default(),
+            }),
+            returned: Condvar::new(),
+        };
+
+        for _ in 0..pool_options.min_size {
+            let container = CompiledUntrustedRustProject::create_shared_container(&pool.compiled_project)?;
+
+            let mut state = pool.lock_state();
+            state.idle.push((container, Instant::now()));
+            state.metrics.size += 1;
+            state.metrics.idle += 1;
+            state.metrics.containers_created += 1;
+        }
+
+        return Ok(pool);
+    }
+
+    /// Takes an idle container, or creates one. Waits for a container to be returned if `max_size` are already checked out.
+    /// The container goes back to the pool when the returned guard is dropped
+    pub fn checkout(&self) -> Result<PooledContainer<'_>> {
+        let mut state = self.lock_state();
+        state.metrics.checkouts += 1;
+
+        let mut waited = false;
+        loop {
+            self.expire_idle_locked(&mut state);
+
+            if let Some((container, _)) = state.idle.pop() {
+                state.metrics.idle -= 1;
+                state.metrics.checked_out += 1;
+                return Ok(self.pooled(container));
+            }
+
+            if state.metrics.size < self.pool_options.max_size {
+                break;
+            }
+
+            if !waited {
+                state.metrics.waits += 1;
+                waited = true;
+            }
+            state = self.returned.wait(state).unwrap_or_else(|err| err.into_inner());
+        }
+
+        // create the container without holding the lock, but count it already so that `max_size` is not exceeded
+        state.metrics.size += 1;
+        state.metrics.checked_out += 1;
+        drop(state);
+
+        return match CompiledUntrustedRustProject::create_shared_container(&self.compiled_project) {
+            Ok(container) => {
+                self.lock_state().metrics.containers_created += 1;
+                Ok(self.pooled(container))
+            },
+            Err(err) => {
+                self.remove_checked_out();
+                Err(err)
+            },
+        };
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let mut state = self.lock_state();
+        self.expire_idle_locked(&mut state);
+        return state.metrics;
+    }
+
+    /// Drops the containers that have been idle for longer than the idle timeout. [`Self::checkout`] and [`Self::metrics`] already do this,
+    /// so this is only needed to free the containers of a pool that is not being used, e.g. from a timer
+    pub fn expire_idle(&self) {
+        let mut state = self.lock_state();
+        self.expire_idle_locked(&mut state);
+    }
+
+    pub fn pool_options(&self) -> PoolOptions {
+        self.pool_options
+    }
+
+    fn pooled(&self, container: Container) -> PooledContainer<'_> {
+        return PooledContainer {
+            pool: self,
+            container: Some(container),
+        };
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, PoolState> {
+        // the state is always consistent between statements, so it is still usable if a thread panicked while holding the lock
+        return self.state.lock().unwrap_or_else(|err| err.into_inner());
+    }
+
+    /// Drops the containers that have been idle for too long, oldest first, keeping at least `min_size` containers
+    fn expire_idle_locked(&self, state: &mut PoolState) {
+        let Some(idle_timeout) = self.pool_options.idle_timeout else {
+            return;
+        };
+
+        let num_expired = state.idle.iter()
+            .take_while(|(_, idle_since)| idle_since.elapsed() >= idle_timeout)
+            .count()
+            .min(state.metrics.size.saturating_sub(self.pool_options.min_size));
+
+        state.idle.drain(..num_expired);
+        state.metrics.size -= num_expired;
+        state.metrics.idle -= num_expired;
+        state.metrics.containers_expired += num_expired as u64;
+    }
+
+    fn check_in(&self, mut container: Container) {
+        container.revoke_cancel_handles();
+        container.set_default_call_options(self.compiled_project.default_call_options());
+
+        let reset = match self.pool_options.reset_mode {
+            // the container re-instantiates itself after a trap, but keeps the broken instance if that failed
+            ResetMode::None if container.needs_reinstantiate() => container.reinstantiate(),
+            ResetMode::None => Ok(()),
+            ResetMode::FreshMemory => container.reinstantiate(),
+            ResetMode::Recreate => CompiledUntrustedRustProject::create_shared_container(&self.compiled_project).map(|new_container| {
+                container = new_container;
+                self.lock_state().metrics.containers_created += 1;
+            }),
+        };
+
+        if let Err(err) = reset {
+            warn!("dropping a pooled container that could not be reset: {}", err);
+            self.lock_state().metrics.reset_failures += 1;
+            self.remove_checked_out();
+            return;
+        }
+
+        let mut state = self.lock_state();
+        state.idle.push((container, Instant::now()));
+        state.metrics.checked_out -= 1;
+        state.metrics.idle += 1;
+        drop(state);
+
+        self.returned.notify_one();
+    }
+
+    /// Forgets a checked out container that will not be returned, which makes room for a new one
+    fn remove_checked_out(&self) {
+        let mut state = self.lock_state();
+        state.metrics.size -= 1;
+        state.metrics.checked_out -= 1;
+        drop(state);
+
+        self.returned.notify_one();
+    }
+}
+
+/// A container that is checked out of a [`ContainerPool`]. It is reset (see [`ResetMode`]) and returned to the pool when dropped
+pub struct PooledContainer<'p> {
+    pool: &'p ContainerPool,
+    /// only `None` while being returned
+    container: Option<Container>,
+}
+
+impl Deref for PooledContainer<'_> {
+    type Target = Container;
+
+    fn deref(&self) -> &Container {
+        self.container.as_ref().expect("the container is only taken when dropped")
+    }
+}
+
+impl DerefMut for PooledContainer<'_> {
+    fn deref_mut(&mut self) -> &mut Container {
+        self.container.as_mut().expect("the container is only taken when dropped")
+    }
+}
+
+impl Drop for PooledContainer<'_> {
+    fn drop(&mut self) {
+        if let Some(container) = self.container.take() {
+            self.pool.check_in(container);
+        }
+    }
+}
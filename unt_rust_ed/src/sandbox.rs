@@ -0,0 +1,248 @@
+use std::env;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::error::*;
+
+/// How often to check if cargo has finished when there is a compile timeout
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Hardens the `cargo build` of the untrusted code.
+///
+/// Proc macros, build scripts and dependencies all get to run arbitrary code on the host at build time,
+/// so the build runs with a cleared environment, a private `CARGO_HOME`, and (on Linux) resource limits.
+/// With [`Self::with_isolated_network`], the build also runs in its own user and network namespace
+#[derive(Clone, Debug)]
+pub struct BuildSandbox {
+    cargo_home: Option<PathBuf>,
+    max_cpu_secs: Option<u64>,
+    max_memory_bytes: Option<u64>,
+    max_file_size_bytes: Option<u64>,
+    max_processes: Option<u64>,
+    isolate_network: bool,
+}
+
+impl Default for BuildSandbox {
+    fn default() -> Self {
+        Self {
+            cargo_home: None,
+            max_cpu_secs: Some(5 * 60),
+            max_memory_bytes: None,
+            max_file_size_bytes: Some(1024 * 1024 * 1024),
+            max_processes: None,
+            isolate_network: false,
+        }
+    }
+}
+
+impl BuildSandbox {
+    /// Every build gets its own empty `CARGO_HOME` unless [`Self::with_cargo_home`] is set. Such a build has to download its dependencies
+    /// (including `extism-pdk` and `serde`, which every project uses), so it cannot be combined with [`Self::with_isolated_network`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// By default every build gets an empty `CARGO_HOME`, so dependencies are downloaded again each time.
+    /// Point this at a directory that is only used for untrusted builds to reuse the downloads (never the host's own `CARGO_HOME`)
+    pub fn with_cargo_home<P: AsRef<Path>>(mut self, cargo_home: P) -> Self {
+        self.cargo_home = Some(cargo_home.as_ref().to_path_buf());
+        self
+    }
+
+    /// `RLIMIT_CPU` for cargo and every process it spawns
+    pub fn with_max_cpu_secs(mut self, secs: Option<u64>) -> Self {
+        self.max_cpu_secs = secs;
+        self
+    }
+
+    /// `RLIMIT_AS` for cargo and every process it spawns. Off by default, since it counts reserved address space rather than memory in use,
+    /// and rustc and the linker reserve far more than they use (a few GiB is enough to kill them on ordinary crates).
+    /// To cap the memory that the build actually uses, run it in a cgroup instead
+    pub fn with_max_memory_bytes(mut self, num_bytes: Option<u64>) -> Self {
+        self.max_memory_bytes = num_bytes;
+        self
+    }
+
+    /// `RLIMIT_FSIZE` for cargo and every process it spawns
+    pub fn with_max_file_size_bytes(mut self, num_bytes: Option<u64>) -> Self {
+        self.max_file_size_bytes = num_bytes;
+        self
+    }
+
+    /// `RLIMIT_NPROC`. Note that Linux counts this against every process of the user, not just the ones spawned by the build
+    pub fn with_max_processes(mut self, num_processes: Option<u64>) -> Self {
+        self.max_processes = num_processes;
+        self
+    }
+
+    /// Runs the build in new user and network namespaces, so build-time code cannot reach the network.
+    /// This also passes `--offline` to cargo, so every dependency must already be in the sandbox's `CARGO_HOME`.
+    /// Requires [`Self::with_cargo_home`], otherwise the build fails with [`UntRustedError::InvalidBuildSandbox`]
+    pub fn with_isolated_network(mut self, isolate_network: bool) -> Self {
+        self.isolate_network = isolate_network;
+        self
+    }
+
+    pub(crate) fn apply(&self, command: &mut Command, cargo_dir: &Path) -> Result<()> {
+        let cargo_home = match &self.cargo_home {
+            Some(cargo_home) => cargo_home.clone(),
+            // `--offline` with an empty `CARGO_HOME` fails for every project, and only after cargo has started
+            None if self.isolate_network => return Err(UntRustedError::InvalidBuildSandbox(
+                "an isolated network needs a `CARGO_HOME` with the dependencies already in it (see `with_cargo_home`)".into())),
+            None => cargo_dir.join(".cargo-home"),
+        };
+
+        // rustup's cargo/rustc proxies still need to find the installed toolchains
+        let rustup_home = env::var_os("RUSTUP_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".rustup")));
+        let rustup_toolchain = env::var_os("RUSTUP_TOOLCHAIN");
+        let path = env::var_os("PATH");
+
+        command.env_clear();
+        command.env("HOME", cargo_dir);
+        command.env("CARGO_HOME", &cargo_home);
+        if let Some(path) = path {
+            command.env("PATH", path);
+        }
+        if let Some(rustup_home) = rustup_home {
+            command.env("RUSTUP_HOME", rustup_home);
+        }
+        if let Some(rustup_toolchain) = rustup_toolchain {
+            command.env("RUSTUP_TOOLCHAIN", rustup_toolchain);
+        }
+
+        if self.isolate_network {
+            command.arg("--offline");
+        }
+
+        self.apply_os_limits(command);
+
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_os_limits(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        let rlimits = [
+            (libc::RLIMIT_CPU, self.max_cpu_secs),
+            (libc::RLIMIT_AS, self.max_memory_bytes),
+            (libc::RLIMIT_FSIZE, self.max_file_size_bytes),
+            (libc::RLIMIT_NPROC, self.max_processes),
+        ];
+        let isolate_network = self.isolate_network;
+
+        // this runs in the forked child before exec, so it must stick to async-signal-safe calls
+        unsafe {
+            command.pre_exec(move || {
+                if isolate_network && libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                for (resource, limit) in rlimits {
+                    if let Some(limit) = limit {
+                        let rlimit = libc::rlimit {
+                            rlim_cur: limit as libc::rlim_t,
+                            rlim_max: limit as libc::rlim_t,
+                        };
+                        if libc::setrlimit(resource, &rlimit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                }
+
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_os_limits(&self, _command: &mut Command) {
+        warn!("build sandbox resource limits and namespaces are only supported on linux, so they are not applied");
+    }
+}
+
+/// Runs the cargo command to completion, killing it (and everything it spawned) if it takes longer than `timeout`
+pub(crate) fn run_cargo(mut command: Command, timeout: Option<Duration>) -> Result<Output> {
+    let Some(timeout) = timeout else {
+        return command.output().map_err(|err| UntRustedError::IoError {
+            resource: "cargo build".into(),
+            err,
+        });
+    };
+
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    // put cargo in its own process group, so that rustc and build scripts can be killed along with it
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let mut child = command.spawn().map_err(|err| UntRustedError::IoError {
+        resource: "cargo build".into(),
+        err,
+    })?;
+
+    // drain the pipes while waiting, otherwise cargo blocks once they fill up
+    let stdout_reader = spawn_pipe_reader(child.stdout.take());
+    let stderr_reader = spawn_pipe_reader(child.stderr.take());
+
+    let start = Instant::now();
+    let status = loop {
+        let status = child.try_wait().map_err(|err| UntRustedError::IoError {
+            resource: "cargo build".into(),
+            err,
+        })?;
+
+        if let Some(status) = status {
+            break status;
+        }
+
+        if start.elapsed() > timeout {
+            debug!("cargo build timed out after {:?}, killing it", timeout);
+            kill_process_group(&mut child);
+            return Err(UntRustedError::CompileTimeout(timeout));
+        }
+
+        thread::sleep(WAIT_POLL_INTERVAL);
+    };
+
+    return Ok(Output {
+        status,
+        stdout: stdout_reader.join().unwrap_or_default(),
+        stderr: stderr_reader.join().unwrap_or_default(),
+    });
+}
+
+fn spawn_pipe_reader<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            if let Err(err) = pipe.read_to_end(&mut buf) {
+                warn!("failed to read cargo output: {}", err);
+            }
+        }
+        buf
+    })
+}
+
+fn kill_process_group(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+
+    if let Err(err) = child.kill() {
+        debug!("failed to kill cargo build: {}", err);
+    }
+
+    let _ = child.wait();
+}
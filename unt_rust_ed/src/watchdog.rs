@@ -0,0 +1,133 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to cancel a call again until it stops, in case the first cancel reached the runtime before the call started
+const RECANCEL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Why the watchdog stopped a call
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Interruption {
+    TimedOut,
+    Cancelled,
+}
+
+enum WatchdogAction {
+    /// watch a call that has to finish by `deadline`, if any
+    Start {
+        cancel_handle: extism::CancelHandle,
+        deadline: Option<Instant>,
+    },
+    /// stop the call that is running, if any
+    Cancel,
+    /// the call finished
+    Stop(Sender<Option<Interruption>>),
+}
+
+/// Cancels the call that is running in a [`crate::Container`] from another thread (see [`crate::Container::cancel_handle`])
+#[derive(Clone)]
+pub struct CancelHandle {
+    tx: Sender<WatchdogAction>,
+}
+
+impl CancelHandle {
+    /// Stops the call that is running, which then fails with [`crate::error::UntRustedError::Cancelled`].
+    /// Does nothing if no call is running
+    pub fn cancel(&self) {
+        let _ = self.tx.send(WatchdogAction::Cancel);
+    }
+}
+
+/// Cancels calls that run past their timeout, or that are cancelled through a [`CancelHandle`].
+/// One thread watches all the calls of a container, and it is only started once it is needed
+#[derive(Default)]
+pub(crate) struct Watchdog {
+    tx: Option<Sender<WatchdogAction>>,
+    /// set once a [`CancelHandle`] has been handed out, since any call may be cancelled after that
+    watch_all_calls: bool,
+}
+
+impl Watchdog {
+    /// Runs `call`, and cancels it through `cancel_handle` if it takes longer than `timeout` or a [`CancelHandle`] is used.
+    /// Also returns why it was cancelled, if it was
+    pub(crate) fn run<T>(&mut self, cancel_handle: extism::CancelHandle, timeout: Option<Duration>, call: impl FnOnce() -> T) -> (T, Option<Interruption>) {
+        if timeout.is_none() && !self.watch_all_calls {
+            return (call(), None);
+        }
+
+        let tx = self.sender();
+        tx.send(WatchdogAction::Start {
+            cancel_handle,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }).expect("the watchdog thread should be running");
+
+        let output = call();
+
+        // wait until the watchdog has stopped, so that it cannot cancel the next call
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+        tx.send(WatchdogAction::Stop(stopped_tx)).expect("the watchdog thread should be running");
+
+        return (output, stopped_rx.recv().unwrap_or(None));
+    }
+
+    pub(crate) fn cancel_handle(&mut self) -> CancelHandle {
+        self.watch_all_calls = true;
+        return CancelHandle {
+            tx: self.sender().clone(),
+        };
+    }
+
+    fn sender(&mut self) -> &Sender<WatchdogAction> {
+        return self.tx.get_or_insert_with(Self::spawn);
+    }
+
+    /// The thread runs until the container and all of its [`CancelHandle`]s are dropped
+    fn spawn() -> Sender<WatchdogAction> {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            while let Ok(action) = rx.recv() {
+                // there is nothing to cancel between calls
+                let WatchdogAction::Start { cancel_handle, deadline } = action else {
+                    Self::acknowledge(action, None);
+                    continue;
+                };
+
+                let mut interruption = None;
+                loop {
+                    let action = match (interruption, deadline) {
+                        (Some(_), _) => rx.recv_timeout(RECANCEL_INTERVAL),
+                        (None, Some(deadline)) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                        (None, None) => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+
+                    match action {
+                        Ok(WatchdogAction::Cancel) => {
+                            interruption.get_or_insert(Interruption::Cancelled);
+                        },
+                        Ok(action) => {
+                            Self::acknowledge(action, interruption);
+                            break;
+                        },
+                        Err(RecvTimeoutError::Timeout) => {
+                            interruption.get_or_insert(Interruption::TimedOut);
+                        },
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+
+                    if interruption.is_some() {
+                        let _ = cancel_handle.cancel();
+                    }
+                }
+            }
+        });
+
+        return tx;
+    }
+
+    fn acknowledge(action: WatchdogAction, interruption: Option<Interruption>) {
+        if let WatchdogAction::Stop(stopped_tx) = action {
+            let _ = stopped_tx.send(interruption);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use proc_macro2::TokenStream;
 
-use syn::{parse_macro_input, DeriveInput, parse_quote, GenericParam, Generics};
+use syn::{parse_macro_input, DeriveInput, parse_quote, GenericParam, Generics, ItemFn, ItemTrait};
 
 use quote::quote;
 
@@ -15,6 +15,192 @@ pub fn exported_host_type(_metadata: proc_macro::TokenStream, input: proc_macro:
     output.into()
 }
 
+/// Makes the function callable from untrusted code, once it is registered with `UntrustedRustProject::with_host_function::<name>()`.
+/// The params and return value are serialized, so they have to be owned types (or exported host types)
+#[proc_macro_attribute]
+pub fn host_function(_metadata: proc_macro::TokenStream, input: proc_macro::TokenStream)
+                 -> proc_macro::TokenStream {
+    let item_fn = parse_macro_input!(input as ItemFn);
+
+    let sig = &item_fn.sig;
+    let vis = &item_fn.vis;
+    let name = &sig.ident;
+    let name_str = name.to_string();
+    let sig_str = quote!(#sig).to_string();
+
+    if sig.asyncness.is_some() || !sig.generics.params.is_empty() {
+        return syn::Error::new_spanned(sig, "host functions cannot be async or generic").to_compile_error().into();
+    }
+
+    let mut arg_names = Vec::new();
+    let mut arg_types = Vec::new();
+    for input in &sig.inputs {
+        let syn::FnArg::Typed(pat_type) = input else {
+            return syn::Error::new_spanned(input, "host functions cannot take self").to_compile_error().into();
+        };
+        let syn::Pat::Ident(pat_ident) = &*pat_type.pat else {
+            return syn::Error::new_spanned(pat_type, "host function params must be plain names").to_compile_error().into();
+        };
+        if let syn::Type::Reference(_) = &*pat_type.ty {
+            return syn::Error::new_spanned(pat_type, "host function params are deserialized, so they must be owned").to_compile_error().into();
+        }
+
+        arg_names.push(pat_ident.ident.clone());
+        arg_types.push(pat_type.ty.clone());
+    }
+
+    let output = quote! {
+        #item_fn
+
+        // only in the type namespace, so it does not clash with the function
+        #[allow(non_camel_case_types)]
+        #vis struct #name {}
+
+        impl unt_rust_ed::HostFunction for #name {
+            fn name() -> &'static str {
+                #name_str
+            }
+
+            fn signature_as_string() -> &'static str {
+                #sig_str
+            }
+
+            fn call(input: &[u8]) -> unt_rust_ed::HostFunctionResult {
+                unt_rust_ed::call_host_function(#name_str, input, |(#(#arg_names,)*): (#(#arg_types,)*)| #name(#(#arg_names),*))
+            }
+        }
+    };
+    output.into()
+}
+
+/// Lets untrusted code implement the trait, once it is registered with `UntrustedRustProject::with_exported_host_trait::<dyn Trait>()`.
+/// Also creates a `{Trait}Proxy` that implements the trait by calling into a `Container` (see `Container::into_proxy`).
+///
+/// Every method has to take `&self`, and its params and return value are serialized, so they have to be owned types (or exported host types).
+/// The trait methods of the proxy panic if the call fails, while its `try_{method}` methods return the error instead
+#[proc_macro_attribute]
+pub fn exported_host_trait(_metadata: proc_macro::TokenStream, input: proc_macro::TokenStream)
+                 -> proc_macro::TokenStream {
+    let item_trait = parse_macro_input!(input as ItemTrait);
+
+    let vis = &item_trait.vis;
+    let trait_name = &item_trait.ident;
+    let trait_name_str = trait_name.to_string();
+    let traitdef_str = quote!(#item_trait).to_string();
+    let proxy_name = syn::Ident::new(&format!("{}Proxy", trait_name), trait_name.span());
+
+    if !item_trait.generics.params.is_empty() {
+        return syn::Error::new_spanned(&item_trait.generics, "exported host traits cannot be generic").to_compile_error().into();
+    }
+
+    let mut method_names = Vec::new();
+    let mut proxy_methods = Vec::new();
+    let mut try_methods = Vec::new();
+    for trait_item in &item_trait.items {
+        let syn::TraitItem::Fn(trait_fn) = trait_item else {
+            return syn::Error::new_spanned(trait_item, "exported host traits can only have methods").to_compile_error().into();
+        };
+
+        let mut sig = trait_fn.sig.clone();
+        if sig.asyncness.is_some() || !sig.generics.params.is_empty() {
+            return syn::Error::new_spanned(&trait_fn.sig, "exported host trait methods cannot be async or generic").to_compile_error().into();
+        }
+
+        let has_ref_self = matches!(sig.receiver(), Some(receiver) if receiver.reference.is_some() && receiver.mutability.is_none());
+        if !has_ref_self {
+            return syn::Error::new_spanned(&trait_fn.sig, "exported host trait methods must take `&self`").to_compile_error().into();
+        }
+
+        let mut arg_names = Vec::new();
+        for (idx, input) in sig.inputs.iter_mut().skip(1).enumerate() {
+            let syn::FnArg::Typed(pat_type) = input else {
+                continue;
+            };
+            if let syn::Type::Reference(_) = &*pat_type.ty {
+                return syn::Error::new_spanned(&*pat_type, "exported host trait params are serialized, so they must be owned").to_compile_error().into();
+            }
+
+            let arg_name = syn::Ident::new(&format!("arg{}", idx), proc_macro2::Span::call_site());
+            *pat_type.pat = parse_quote!(#arg_name);
+            arg_names.push(arg_name);
+        }
+
+        let output_type = match &sig.output {
+            syn::ReturnType::Default => quote!(()),
+            syn::ReturnType::Type(_, ty) => quote!(#ty),
+        };
+
+        let method_name = sig.ident.to_string();
+        let call_name = format!("{}::{}", trait_name, method_name);
+        method_names.push(method_name);
+
+        let try_name = syn::Ident::new(&format!("try_{}", sig.ident), sig.ident.span());
+        let try_doc = format!("Like `{}`, but returns the error (e.g. a guest panic, trap or timeout) instead of panicking", call_name);
+        let inputs = &sig.inputs;
+        try_methods.push(quote! {
+            #[doc = #try_doc]
+            pub fn #try_name(#inputs) -> unt_rust_ed::error::Result<#output_type> {
+                let unt_rust_ed::Json(output): unt_rust_ed::Json<#output_type> = self.container
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .call_args(#call_name, (#(#arg_names,)*))?;
+                Ok(output)
+            }
+        });
+
+        proxy_methods.push(quote! {
+            #sig {
+                self.#try_name(#(#arg_names),*).unwrap_or_else(|err| panic!("{} failed: {}", #call_name, err))
+            }
+        });
+    }
+
+    let output = quote! {
+        #item_trait
+
+        /// Implements the trait by calling the untrusted code in a container. The trait methods panic if the call fails,
+        /// so use the `try_` methods where the untrusted code may fail (e.g. panic, trap, run out of fuel or be cancelled)
+        #vis struct #proxy_name {
+            container: ::std::sync::Mutex<unt_rust_ed::Container>,
+        }
+
+        impl #proxy_name {
+            pub fn into_container(self) -> unt_rust_ed::Container {
+                self.container.into_inner().unwrap_or_else(|err| err.into_inner())
+            }
+
+            #(#try_methods)*
+        }
+
+        impl #trait_name for #proxy_name {
+            #(#proxy_methods)*
+        }
+
+        impl unt_rust_ed::ExportedHostTrait for dyn #trait_name {
+            type Proxy = #proxy_name;
+
+            fn trait_name() -> &'static str {
+                #trait_name_str
+            }
+
+            fn traitdef_as_string() -> &'static str {
+                #traitdef_str
+            }
+
+            fn method_names() -> &'static [&'static str] {
+                &[#(#method_names),*]
+            }
+
+            fn create_proxy(container: unt_rust_ed::Container) -> Self::Proxy {
+                #proxy_name {
+                    container: ::std::sync::Mutex::new(container),
+                }
+            }
+        }
+    };
+    output.into()
+}
+
 #[proc_macro_derive(ExportedHostType)]
 pub fn exported_host_type_macro(initial_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let defstr = initial_input.to_string();